@@ -3,7 +3,7 @@ use log::{debug, error, info};
 
 use std::{iter, time::Instant};
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 
 use wgpu::util::DeviceExt;
 
@@ -12,16 +12,22 @@ use winit::{
     event::{ElementState, Event, KeyEvent, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
     keyboard::{KeyCode, PhysicalKey},
-    window::{Window, WindowBuilder},
+    window::{Fullscreen, Window, WindowBuilder},
 };
 
-use crate::Args;
 #[allow(unused_imports)]
 use crate::{
     colors::Rgba,
-    raytracer::{render, RenderMode},
+    raytracer::{
+        camera::Camera,
+        gpu::{fit_resolution, request_device, GpuRaytracer},
+        render,
+        scene::Scene,
+        RenderMode,
+    },
     texture::Texture,
 };
+use crate::{controller::CameraController, Args};
 
 #[allow(dead_code)]
 pub struct GraphicsState<'a> {
@@ -31,11 +37,14 @@ pub struct GraphicsState<'a> {
     surface: wgpu::Surface<'a>,
     config: wgpu::SurfaceConfiguration,
     render_pipeline: wgpu::RenderPipeline,
-    compute_pipeline: wgpu::ComputePipeline,
+    raytracer: GpuRaytracer,
+    render_each_frame: bool,
+    camera: Camera,
     vertex_buffer: wgpu::Buffer,
-    compute_parameters_buffer: wgpu::Buffer,
+    output_texture_bind_group_layout: wgpu::BindGroupLayout,
     output_texture_bind_group: wgpu::BindGroup,
     compute_bind_group: wgpu::BindGroup,
+    accumulation_buffer: wgpu::Buffer,
     window: &'a Window,
 }
 
@@ -45,6 +54,10 @@ struct Vertex {
     position: [f32; 3],
 }
 
+const OUTPUT_TEXTURE_USAGES: wgpu::TextureUsages = wgpu::TextureUsages::COPY_DST
+    .union(wgpu::TextureUsages::STORAGE_BINDING)
+    .union(wgpu::TextureUsages::TEXTURE_BINDING);
+
 const FULL_SCREEN_QUAD: [Vertex; 6] = [
     Vertex {
         position: [-1.0, 1.0, 0.0],
@@ -68,31 +81,29 @@ const FULL_SCREEN_QUAD: [Vertex; 6] = [
 
 #[allow(dead_code)]
 impl<'a> GraphicsState<'a> {
-    pub async fn new(window: &'a Window, args: &Args) -> Result<Self> {
+    pub async fn new(
+        window: &'a Window,
+        args: &Args,
+        scene: &Scene,
+        prerendered: Option<Texture>,
+    ) -> Result<Self> {
         //WGPU Instance
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
             backends: wgpu::Backends::all(),
             ..Default::default()
         });
 
-        //Adapter
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::HighPerformance,
-                ..Default::default()
-            })
-            .await
-            .context("Failed to get adapter")?;
-        info!("{:?}", adapter.get_info());
-
-        //Device & Queue
-        let (device, queue) = adapter.request_device(&Default::default(), None).await?;
-
         //Surface
-        let surface_size = window.inner_size();
-
         let surface = instance.create_surface(window)?;
 
+        //Adapter, Device & Queue
+        let (adapter, device, queue) = request_device(&instance, Some(&surface)).await?;
+
+        //The window can be larger than what the GPU output can cover
+        let window_size = window.inner_size();
+        let (width, height) =
+            fit_resolution(&device.limits(), (window_size.width, window_size.height));
+
         let surface_capabilities = surface.get_capabilities(&adapter);
 
         let surface_format = surface_capabilities
@@ -105,8 +116,8 @@ impl<'a> GraphicsState<'a> {
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
             format: surface_format,
-            width: surface_size.width,
-            height: surface_size.height,
+            width,
+            height,
             present_mode: surface_capabilities.present_modes[0],
             alpha_mode: surface_capabilities.alpha_modes[0],
             view_formats: vec![],
@@ -118,8 +129,6 @@ impl<'a> GraphicsState<'a> {
         //Shaders
         let texture_shader_module =
             device.create_shader_module(wgpu::include_wgsl!("shaders/texture/shader.wgsl"));
-        let compute_shader_module =
-            device.create_shader_module(wgpu::include_wgsl!("shaders/compute/shader.wgsl"));
 
         //Vertex Buffer
         let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -138,32 +147,47 @@ impl<'a> GraphicsState<'a> {
             }],
         };
         //Texture Binding
-        let mut texture = Texture::new(
-            vec![Rgba::<u8>::default(); (surface_size.width * surface_size.height) as usize],
-            surface_size.width as usize,
-            surface_size.height as usize,
-        );
+        let render_each_frame = args.mode == RenderMode::Gpu && prerendered.is_none();
+
+        let texture = match prerendered {
+            Some(texture) => texture,
+            None => {
+                let mut texture = Texture::new(
+                    vec![Rgba::<u8>::default(); (width * height) as usize],
+                    width as usize,
+                    height as usize,
+                );
+
+                match args.mode {
+                    RenderMode::SingleThread => render(
+                        &mut texture,
+                        scene,
+                        args.samples,
+                        args.max_bounces,
+                        RenderMode::SingleThread,
+                        None,
+                    )?,
+                    RenderMode::MultiThread => render(
+                        &mut texture,
+                        scene,
+                        args.samples,
+                        args.max_bounces,
+                        RenderMode::MultiThread,
+                        None,
+                    )?,
+                    RenderMode::Gpu => (),
+                }
 
-        match args.mode {
-            RenderMode::SingleThread => {
-                render(&mut texture, args.samples, RenderMode::SingleThread)?
+                texture
             }
-            RenderMode::MultiThread => render(&mut texture, args.samples, RenderMode::MultiThread)?,
-            RenderMode::Gpu => (),
-        }
+        };
 
-        let texture = texture.into_wgpu_texture(
-            &device,
-            &queue,
-            wgpu::TextureUsages::COPY_DST
-                | wgpu::TextureUsages::STORAGE_BINDING
-                | wgpu::TextureUsages::TEXTURE_BINDING,
-        );
+        let texture = texture.into_wgpu_texture(&device, &queue, OUTPUT_TEXTURE_USAGES);
 
         let texture_view = texture.create_view(&Default::default());
 
         //Texture output bind group
-        let output_texture_binding_group_layout =
+        let output_texture_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("Image binding group layout"),
                 entries: &[wgpu::BindGroupLayoutEntry {
@@ -178,19 +202,16 @@ impl<'a> GraphicsState<'a> {
                 }],
             });
 
-        let output_texture_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Image binding group layout"),
-            layout: &output_texture_binding_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: wgpu::BindingResource::TextureView(&texture_view),
-            }],
-        });
+        let output_texture_bind_group = create_output_texture_bind_group(
+            &device,
+            &output_texture_bind_group_layout,
+            &texture_view,
+        );
 
         //Render Pipeline
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: None,
-            bind_group_layouts: &[&output_texture_binding_group_layout],
+            bind_group_layouts: &[&output_texture_bind_group_layout],
             push_constant_ranges: &[],
         });
 
@@ -228,74 +249,11 @@ impl<'a> GraphicsState<'a> {
             cache: None,
         });
 
-        //Compute bind group
-        let compute_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: None,
-                entries: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Uniform,
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::StorageTexture {
-                            access: wgpu::StorageTextureAccess::WriteOnly,
-                            format: wgpu::TextureFormat::Rgba8Unorm,
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                        },
-                        count: None,
-                    },
-                ],
-            });
-
-        let compute_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: 12,
-            usage: wgpu::BufferUsages::COPY_DST
-                | wgpu::BufferUsages::STORAGE
-                | wgpu::BufferUsages::UNIFORM,
-            mapped_at_creation: false,
-        });
-
-        let compute_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout: &compute_bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: compute_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::TextureView(&texture_view),
-                },
-            ],
-        });
-
-        //Compute Pipeline
-        let compute_pipeline_layout =
-            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Compute pipeline layout"),
-                bind_group_layouts: &[&compute_bind_group_layout],
-                push_constant_ranges: &[],
-            });
-
-        let compute_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-            label: Some("Compute pipeline"),
-            layout: Some(&compute_pipeline_layout),
-            module: &compute_shader_module,
-            entry_point: "main",
-            compilation_options: Default::default(),
-            cache: None,
-        });
+        //Compute
+        let raytracer = GpuRaytracer::new(&device, scene, args.workgroup_size)?;
+        let accumulation_buffer = raytracer.create_accumulation_buffer(&device, (width, height));
+        let compute_bind_group =
+            raytracer.create_bind_group(&device, &texture_view, &accumulation_buffer);
 
         Ok(GraphicsState {
             adapter,
@@ -304,12 +262,15 @@ impl<'a> GraphicsState<'a> {
             surface,
             config,
             render_pipeline,
-            compute_pipeline,
+            raytracer,
+            render_each_frame,
+            camera: scene.camera,
             vertex_buffer,
-            compute_parameters_buffer: compute_buffer,
+            output_texture_bind_group_layout,
             output_texture_bind_group,
             window,
             compute_bind_group,
+            accumulation_buffer,
         })
     }
 
@@ -318,14 +279,44 @@ impl<'a> GraphicsState<'a> {
     }
 
     fn resize(&mut self, new_size: &PhysicalSize<u32>) {
-        if new_size.width > 0 && new_size.height > 0 {
-            self.config.width = new_size.width;
-            self.config.height = new_size.height;
-            self.surface.configure(&self.device, &self.config);
+        let (width, height) =
+            fit_resolution(&self.device.limits(), (new_size.width, new_size.height));
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        self.config.width = width;
+        self.config.height = height;
+        self.surface.configure(&self.device, &self.config);
+
+        //Prerendered images are shown as is, the GPU output follows the window size
+        if self.render_each_frame {
+            let texture = Texture::new(
+                vec![Rgba::<u8>::default(); (width * height) as usize],
+                width as usize,
+                height as usize,
+            )
+            .into_wgpu_texture(&self.device, &self.queue, OUTPUT_TEXTURE_USAGES);
+            let texture_view = texture.create_view(&Default::default());
+
+            self.output_texture_bind_group = create_output_texture_bind_group(
+                &self.device,
+                &self.output_texture_bind_group_layout,
+                &texture_view,
+            );
+            self.accumulation_buffer = self
+                .raytracer
+                .create_accumulation_buffer(&self.device, (width, height));
+            self.compute_bind_group = self.raytracer.create_bind_group(
+                &self.device,
+                &texture_view,
+                &self.accumulation_buffer,
+            );
+            self.raytracer.reset_accumulation();
         }
     }
 
-    fn render(&self, args: &Args) -> Result<()> {
+    fn render(&mut self, args: &Args) -> Result<()> {
         let now = Instant::now();
 
         let output: wgpu::SurfaceTexture = self.surface.get_current_texture()?;
@@ -335,27 +326,15 @@ impl<'a> GraphicsState<'a> {
         let mut encoder = self.device.create_command_encoder(&Default::default());
 
         //Compute if in gpu mode
-        if args.mode == RenderMode::Gpu {
-            let buffer = [self.config.width, self.config.height, args.samples];
-            let compute_buffer =
-                self.device
-                    .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                        label: None,
-                        contents: bytemuck::cast_slice(&buffer),
-                        usage: wgpu::BufferUsages::COPY_SRC,
-                    });
-            encoder.copy_buffer_to_buffer(
-                &compute_buffer,
-                0,
-                &self.compute_parameters_buffer,
-                0,
-                12,
+        if self.render_each_frame {
+            self.raytracer.dispatch(
+                &self.queue,
+                &mut encoder,
+                &self.compute_bind_group,
+                (self.config.width, self.config.height),
+                args.samples,
+                args.max_bounces,
             );
-
-            let mut compute_pass = encoder.begin_compute_pass(&Default::default());
-            compute_pass.set_pipeline(&self.compute_pipeline);
-            compute_pass.set_bind_group(0, &self.compute_bind_group, &[]);
-            compute_pass.dispatch_workgroups(self.config.width / 16, self.config.height / 16, 1);
         }
 
         //Render (set texture to surface)
@@ -385,7 +364,7 @@ impl<'a> GraphicsState<'a> {
 
         output.present();
 
-        if args.mode == RenderMode::Gpu {
+        if self.render_each_frame {
             let elapsed = now.elapsed();
             info!("Elapsed: {:.2?}", elapsed);
         }
@@ -394,93 +373,127 @@ impl<'a> GraphicsState<'a> {
     }
 }
 
+fn create_output_texture_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    texture_view: &wgpu::TextureView,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Image binding group layout"),
+        layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: wgpu::BindingResource::TextureView(texture_view),
+        }],
+    })
+}
+
 //Hols the app state
 pub struct Application<'a> {
     state: GraphicsState<'a>,
+    controller: CameraController,
     args: Args,
 }
 
 impl<'a> Application<'a> {
-    async fn new(window: &'a Window, args: Args) -> Result<Self> {
-        let state = GraphicsState::new(window, &args).await?;
-
-        Ok(Application { state, args })
+    async fn new(
+        window: &'a Window,
+        args: Args,
+        scene: &Scene,
+        prerendered: Option<Texture>,
+    ) -> Result<Self> {
+        let state = GraphicsState::new(window, &args, scene, prerendered).await?;
+
+        Ok(Application {
+            state,
+            controller: CameraController::new(),
+            args,
+        })
     }
 
-    fn render(&self) -> Result<()> {
+    fn render(&mut self) -> Result<()> {
         self.state.render(&self.args)?;
 
         Ok(())
     }
 
-    fn update(&self) {}
+    //Moves the camera, which restarts the accumulation of the GPU render
+    fn update(&mut self) {
+        let state = &mut self.state;
+        if state.render_each_frame && self.controller.update(&mut state.camera) {
+            state.raytracer.update_camera(&state.queue, &state.camera);
+        }
+    }
 
+    //Returns whether the event was used, the camera only moves when rendering on the GPU
     fn input(&mut self, event: &WindowEvent) -> bool {
-        matches!(
-            event,
-            WindowEvent::KeyboardInput {
-                event: KeyEvent {
-                    physical_key: PhysicalKey::Code(KeyCode::Escape),
-                    state: _,
-                    ..
-                },
-                ..
-            }
-        )
+        self.state.render_each_frame && self.controller.input(event)
     }
 
-    pub async fn run(args: Args) -> Result<()> {
+    //Opens the window, `prerendered` is displayed as is instead of rendering the scene
+    pub async fn run(args: Args, scene: Scene, prerendered: Option<Texture>) -> Result<()> {
         let event_loop = EventLoop::new().expect("Failed to create event_loop");
         event_loop.set_control_flow(ControlFlow::Poll);
 
-        let scale = 1.0;
+        let (width, height) = args.resolution()?;
 
         //Create the window
         let window = WindowBuilder::new()
             .with_title("WGPU_Raytracer")
-            .with_resizable(false)
-            .with_inner_size(PhysicalSize {
-                width: 1920.0 * scale,
-                height: 1080.0 * scale,
-            })
+            .with_inner_size(PhysicalSize { width, height })
             .build(&event_loop)?;
 
         //Create the main application
-        let mut app = Application::new(&window, args).await?;
+        let mut app = Application::new(&window, args, &scene, prerendered).await?;
 
         //Main loop
         event_loop.run(move |event, window| match event {
             Event::WindowEvent {
                 ref event,
                 window_id: _,
-            } => {
-                if !app.input(event) {
-                    match event {
-                        //Close
-                        WindowEvent::CloseRequested
-                        | WindowEvent::KeyboardInput {
-                            event:
-                                KeyEvent {
-                                    state: ElementState::Pressed,
-                                    physical_key: PhysicalKey::Code(KeyCode::Escape),
-                                    ..
-                                },
-                            ..
-                        } => window.exit(),
-                        //Resize
-                        WindowEvent::Resized(physical_size) => {
-                            app.state.resize(physical_size);
+            } if !app.input(event) => {
+                match event {
+                    //Close
+                    WindowEvent::CloseRequested
+                    | WindowEvent::KeyboardInput {
+                        event:
+                            KeyEvent {
+                                state: ElementState::Pressed,
+                                physical_key: PhysicalKey::Code(KeyCode::Escape),
+                                ..
+                            },
+                        ..
+                    } => window.exit(),
+                    //Fullscreen
+                    WindowEvent::KeyboardInput {
+                        event:
+                            KeyEvent {
+                                state: ElementState::Pressed,
+                                physical_key: PhysicalKey::Code(KeyCode::F11),
+                                repeat: false,
+                                ..
+                            },
+                        ..
+                    } => {
+                        let window = app.state.window();
+                        match window.fullscreen() {
+                            Some(_) => window.set_fullscreen(None),
+                            None => window.set_fullscreen(Some(Fullscreen::Borderless(None))),
                         }
-                        //Redraw
-                        WindowEvent::RedrawRequested => {
-                            app.update();
+                    }
+                    //Resize
+                    WindowEvent::Resized(physical_size) => {
+                        app.state.resize(physical_size);
+                    }
+                    //Redraw
+                    WindowEvent::RedrawRequested => {
+                        app.update();
 
-                            if let Err(e) = app.render() {
-                                error!("{e}");
-                            };
-                        }
-                        _ => (),
+                        if let Err(e) = app.render() {
+                            error!("{e}");
+                        };
                     }
+                    _ => (),
                 }
             }
             Event::AboutToWait => app.state.window.request_redraw(),
@@ -3,10 +3,11 @@
 use std::ops::{self, Deref};
 
 use bytemuck::{Pod, Zeroable};
+use serde::Deserialize;
 
 /*     RGB<T>    */
 #[repr(C)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Deserialize)]
 pub struct Rgb<T>(pub [T; 3]);
 
 impl<T> Deref for Rgb<T> {
@@ -62,13 +63,10 @@ impl Rgb<f32> {
     pub const GRAY: Rgb<f32> = Rgb([0.5, 0.5, 0.5]);
 }
 
+//Rounded like the GPU does when storing to a unorm texture
 impl From<Rgb<f32>> for Rgb<u8> {
     fn from(value: Rgb<f32>) -> Self {
-        Self([
-            (value.0[0] * 255.0) as u8,
-            (value.0[1] * 255.0) as u8,
-            (value.0[2] * 255.0) as u8,
-        ])
+        Self(value.0.map(|channel| (channel * 255.0).round() as u8))
     }
 }
 
@@ -104,6 +102,26 @@ impl ops::Mul for Rgb<f32> {
     }
 }
 
+impl ops::Mul<f32> for Rgb<f32> {
+    type Output = Self;
+
+    fn mul(self, rhs: f32) -> Self {
+        Self([self.0[0] * rhs, self.0[1] * rhs, self.0[2] * rhs])
+    }
+}
+
+impl ops::Add for Rgb<f32> {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self([
+            self.0[0] + rhs.0[0],
+            self.0[1] + rhs.0[1],
+            self.0[2] + rhs.0[2],
+        ])
+    }
+}
+
 impl ops::MulAssign for Rgb<f32> {
     fn mul_assign(&mut self, rhs: Self) {
         self.0[0] *= rhs.0[0];
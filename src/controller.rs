@@ -0,0 +1,125 @@
+use std::time::Instant;
+
+use glam::{Vec2, Vec3};
+use winit::{
+    dpi::PhysicalPosition,
+    event::{ElementState, KeyEvent, MouseButton, MouseScrollDelta, WindowEvent},
+    keyboard::{KeyCode, PhysicalKey},
+};
+
+use crate::raytracer::camera::Camera;
+
+//Radians turned per pixel the mouse moves
+const MOUSE_SENSITIVITY: f32 = 0.003;
+//Speed change for each step of the mouse wheel
+const SPEED_STEP: f32 = 1.25;
+
+//Fly-through controls, WASD to move, Space and Shift to go up and down, drag to look around
+//The mouse wheel changes the speed
+pub struct CameraController {
+    //Units per second
+    speed: f32,
+    //Which of `MOVEMENT_KEYS` are held
+    pressed: [bool; 6],
+    looking: bool,
+    cursor: Option<PhysicalPosition<f64>>,
+    mouse_delta: Vec2,
+    last_update: Instant,
+}
+
+const MOVEMENT_KEYS: [(KeyCode, Vec3); 6] = [
+    (KeyCode::KeyD, Vec3::X),
+    (KeyCode::KeyA, Vec3::NEG_X),
+    (KeyCode::Space, Vec3::Y),
+    (KeyCode::ShiftLeft, Vec3::NEG_Y),
+    (KeyCode::KeyW, Vec3::Z),
+    (KeyCode::KeyS, Vec3::NEG_Z),
+];
+
+impl CameraController {
+    pub fn new() -> Self {
+        Self {
+            speed: 1.0,
+            pressed: [false; 6],
+            looking: false,
+            cursor: None,
+            mouse_delta: Vec2::ZERO,
+            last_update: Instant::now(),
+        }
+    }
+
+    //Returns whether the event was used
+    pub fn input(&mut self, event: &WindowEvent) -> bool {
+        match event {
+            WindowEvent::KeyboardInput {
+                event:
+                    KeyEvent {
+                        physical_key: PhysicalKey::Code(key),
+                        state,
+                        repeat: false,
+                        ..
+                    },
+                ..
+            } => {
+                let Some(index) = MOVEMENT_KEYS.iter().position(|(code, _)| code == key) else {
+                    return false;
+                };
+                self.pressed[index] = *state == ElementState::Pressed;
+                true
+            }
+            WindowEvent::MouseInput {
+                button: MouseButton::Left,
+                state,
+                ..
+            } => {
+                self.looking = *state == ElementState::Pressed;
+                true
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                if let (true, Some(cursor)) = (self.looking, self.cursor) {
+                    self.mouse_delta += Vec2::new(
+                        (position.x - cursor.x) as f32,
+                        (position.y - cursor.y) as f32,
+                    );
+                }
+                self.cursor = Some(*position);
+                true
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                let steps = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y,
+                    MouseScrollDelta::PixelDelta(position) => position.y as f32 / 50.0,
+                };
+                self.speed *= SPEED_STEP.powf(steps);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    //Applies the input received since the last update, returns whether the camera moved
+    pub fn update(&mut self, camera: &mut Camera) -> bool {
+        let elapsed = self.last_update.elapsed().as_secs_f32();
+        self.last_update = Instant::now();
+
+        //Right, up and forward movement
+        let movement: Vec3 = MOVEMENT_KEYS
+            .iter()
+            .zip(self.pressed)
+            .filter(|(_, pressed)| *pressed)
+            .map(|((_, direction), _)| *direction)
+            .sum();
+        let mouse_delta = std::mem::take(&mut self.mouse_delta);
+        if movement == Vec3::ZERO && mouse_delta == Vec2::ZERO {
+            return false;
+        }
+
+        camera.rotate(
+            mouse_delta.x * MOUSE_SENSITIVITY,
+            -mouse_delta.y * MOUSE_SENSITIVITY,
+        );
+        camera.translate(movement.normalize_or_zero() * self.speed * elapsed);
+
+        true
+    }
+}
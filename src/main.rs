@@ -1,13 +1,17 @@
 mod app;
 mod colors;
+mod controller;
 mod raytracer;
 mod texture;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use app::Application;
 use clap::Parser;
-use log::trace;
-use raytracer::RenderMode;
+use colors::Rgba;
+use log::{info, trace};
+use raytracer::{gpu, render, scene::Scene, RenderMode};
+use std::path::PathBuf;
+use texture::Texture;
 
 #[derive(Parser, Debug)]
 pub struct Args {
@@ -15,6 +19,55 @@ pub struct Args {
     mode: RenderMode,
     #[arg(short, long, default_value = "128")]
     samples: u32,
+    #[arg(long, default_value = "12", value_parser = clap::value_parser!(u32).range(1..))]
+    max_bounces: u32,
+    #[arg(long, default_value = "1920", value_parser = clap::value_parser!(u32).range(1..))]
+    width: u32,
+    #[arg(long, default_value = "1080", value_parser = clap::value_parser!(u32).range(1..))]
+    height: u32,
+    #[arg(long, default_value = "1.0")]
+    scale: f64,
+    //Side of the square compute workgroups of the GPU mode
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    workgroup_size: Option<u32>,
+    #[arg(long, value_name = "PATH")]
+    scene: Option<PathBuf>,
+    #[arg(short, long, value_name = "FILE.png")]
+    output: Option<PathBuf>,
+    #[arg(long, requires = "output")]
+    headless: bool,
+}
+
+impl Args {
+    //Size of the rendered image, `width` and `height` multiplied by `scale`
+    pub fn resolution(&self) -> Result<(u32, u32)> {
+        if !(self.scale.is_finite() && self.scale > 0.0) {
+            bail!("Invalid scale {}, expected a positive number", self.scale);
+        }
+
+        let width = (self.width as f64 * self.scale).round();
+        let height = (self.height as f64 * self.scale).round();
+
+        let max_size = wgpu::Limits::default().max_texture_dimension_2d as f64;
+        if width < 1.0 || height < 1.0 || width > max_size || height > max_size {
+            bail!(
+                "Invalid resolution {width}x{height} ({}x{} at scale {}), both sides must be between 1 and {max_size}",
+                self.width,
+                self.height,
+                self.scale
+            );
+        }
+
+        //Checked against the default limits, which every adapter supports
+        let max_pixel_count = gpu::max_pixel_count(&wgpu::Limits::default());
+        if self.mode == RenderMode::Gpu && width * height > max_pixel_count as f64 {
+            bail!(
+                "Invalid resolution {width}x{height}, the GPU mode renders at most {max_pixel_count} pixels"
+            );
+        }
+
+        Ok((width as u32, height as u32))
+    }
 }
 
 fn main() -> Result<()> {
@@ -24,7 +77,42 @@ fn main() -> Result<()> {
     let args = Args::parse();
     trace!("Parsed args");
 
-    pollster::block_on(Application::run(args))?;
+    let (width, height) = args.resolution()?;
+
+    let scene = match &args.scene {
+        Some(path) => Scene::load(path)?,
+        None => Scene::default(),
+    };
+    trace!("Loaded scene");
+
+    //Offline render, saved to disk
+    let prerendered = match &args.output {
+        Some(output) => {
+            let (width, height) = (width as usize, height as usize);
+            let mut texture =
+                Texture::new(vec![Rgba::<u8>::default(); width * height], width, height);
+
+            render(
+                &mut texture,
+                &scene,
+                args.samples,
+                args.max_bounces,
+                args.mode.clone(),
+                args.workgroup_size,
+            )?;
+            texture.save_png(output)?;
+            info!("Saved render to {}", output.display());
+
+            Some(texture)
+        }
+        None => None,
+    };
+
+    if args.headless {
+        return Ok(());
+    }
+
+    pollster::block_on(Application::run(args, scene, prerendered))?;
 
     Ok(())
 }
@@ -0,0 +1,298 @@
+use glam::Vec3;
+
+use super::{ray::Ray, RaycastHit};
+
+//Axis aligned bounding box
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub const EMPTY: Aabb = Aabb {
+        min: Vec3::INFINITY,
+        max: Vec3::NEG_INFINITY,
+    };
+
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Self { min, max }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn grow(&self, point: Vec3) -> Aabb {
+        Aabb {
+            min: self.min.min(point),
+            max: self.max.max(point),
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let size = (self.max - self.min).max(Vec3::ZERO);
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+
+    //Slab test, returns the entry distance if the box is hit before `max_distance`
+    pub fn intersect(&self, ray: &Ray, inverse_direction: Vec3, max_distance: f32) -> Option<f32> {
+        let t_0 = (self.min - ray.origin) * inverse_direction;
+        let t_1 = (self.max - ray.origin) * inverse_direction;
+
+        let t_near = t_0.min(t_1).max_element().max(0.0);
+        let t_far = t_0.max(t_1).min_element().min(max_distance);
+
+        (t_near <= t_far).then_some(t_near)
+    }
+}
+
+//Flattened node, children of interior nodes are stored depth first:
+//the left child directly follows its parent, `first` is the index of the right child.
+//Leaves reference `count` primitives starting at `first` in `Bvh::primitives`.
+#[derive(Clone, Copy, Debug)]
+pub struct BvhNode {
+    pub bounds: Aabb,
+    pub first: u32,
+    pub count: u32,
+}
+
+impl BvhNode {
+    pub fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+}
+
+const BIN_COUNT: usize = 16;
+pub const MAX_LEAF_SIZE: usize = 4;
+const MAX_DEPTH: usize = 64;
+//Cost of traversing a node relative to intersecting a primitive
+const TRAVERSAL_COST: f32 = 1.0;
+
+//Bounding volume hierarchy built with the surface area heuristic
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+    pub primitives: Vec<usize>,
+}
+
+impl Bvh {
+    pub fn new(bounds: &[Aabb]) -> Self {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(2 * bounds.len()),
+            primitives: (0..bounds.len()).collect(),
+        };
+
+        if !bounds.is_empty() {
+            let centroids: Vec<Vec3> = bounds.iter().map(Aabb::centroid).collect();
+            bvh.build_node(bounds, &centroids, 0, bounds.len(), 0);
+        }
+
+        bvh
+    }
+
+    fn build_node(
+        &mut self,
+        bounds: &[Aabb],
+        centroids: &[Vec3],
+        start: usize,
+        end: usize,
+        depth: usize,
+    ) {
+        let primitives = &mut self.primitives[start..end];
+
+        let node_bounds = primitives
+            .iter()
+            .fold(Aabb::EMPTY, |acc, &i| acc.union(&bounds[i]));
+        let centroid_bounds = primitives
+            .iter()
+            .fold(Aabb::EMPTY, |acc, &i| acc.grow(centroids[i]));
+
+        let node_index = self.nodes.len();
+        self.nodes.push(BvhNode {
+            bounds: node_bounds,
+            first: start as u32,
+            count: (end - start) as u32,
+        });
+
+        let count = end - start;
+        if count == 1 || depth + 1 >= MAX_DEPTH {
+            return;
+        }
+
+        //Primitives are split by the same bins they were counted in, so that no side ends up empty
+        let bin_of = |i: usize, axis: usize| {
+            let min = centroid_bounds.min[axis];
+            let extent = centroid_bounds.max[axis] - min;
+            (((centroids[i][axis] - min) / extent * BIN_COUNT as f32) as usize).min(BIN_COUNT - 1)
+        };
+
+        //Find the cheapest split among the bin boundaries of each axis
+        let mut best_split: Option<(f32, usize, usize)> = None;
+        for axis in [0, 1, 2] {
+            if centroid_bounds.max[axis] - centroid_bounds.min[axis] <= 0.0 {
+                continue;
+            }
+
+            let mut bins = [(Aabb::EMPTY, 0usize); BIN_COUNT];
+            for &i in primitives.iter() {
+                let bin = &mut bins[bin_of(i, axis)];
+                bin.0 = bin.0.union(&bounds[i]);
+                bin.1 += 1;
+            }
+
+            let mut right_costs = [0.0; BIN_COUNT];
+            let (mut right_bounds, mut right_count) = (Aabb::EMPTY, 0);
+            for bin in (1..BIN_COUNT).rev() {
+                right_bounds = right_bounds.union(&bins[bin].0);
+                right_count += bins[bin].1;
+                right_costs[bin] = right_bounds.surface_area() * right_count as f32;
+            }
+
+            let (mut left_bounds, mut left_count) = (Aabb::EMPTY, 0);
+            for bin in 0..BIN_COUNT - 1 {
+                left_bounds = left_bounds.union(&bins[bin].0);
+                left_count += bins[bin].1;
+                if left_count == 0 || left_count == count {
+                    continue;
+                }
+
+                let cost = TRAVERSAL_COST
+                    + (left_bounds.surface_area() * left_count as f32 + right_costs[bin + 1])
+                        / node_bounds.surface_area().max(f32::EPSILON);
+
+                if best_split.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                    best_split = Some((cost, axis, bin));
+                }
+            }
+        }
+
+        let leaf_cost = count as f32;
+        let mid = match best_split {
+            Some((cost, _, _)) if cost >= leaf_cost && count <= MAX_LEAF_SIZE => return,
+            Some((_, axis, bin)) => partition(primitives, |&i| bin_of(i, axis) <= bin),
+            //All centroids are identical, split arbitrarily if there are too many primitives
+            None if count <= MAX_LEAF_SIZE => return,
+            None => count / 2,
+        };
+
+        let mid = start + mid;
+        self.build_node(bounds, centroids, start, mid, depth + 1);
+        let right_child = self.nodes.len() as u32;
+        self.build_node(bounds, centroids, mid, end, depth + 1);
+
+        self.nodes[node_index].first = right_child;
+        self.nodes[node_index].count = 0;
+    }
+
+    //Index of the node following each subtree in depth first order, used for stackless traversal
+    pub fn skip_indices(&self) -> Vec<u32> {
+        let mut skip_indices = vec![0; self.nodes.len()];
+        if self.nodes.is_empty() {
+            return skip_indices;
+        }
+
+        let mut stack = vec![(0, self.nodes.len() as u32)];
+        while let Some((node_index, skip_index)) = stack.pop() {
+            skip_indices[node_index] = skip_index;
+
+            let node = &self.nodes[node_index];
+            if !node.is_leaf() {
+                stack.push((node_index + 1, node.first));
+                stack.push((node.first as usize, skip_index));
+            }
+        }
+
+        skip_indices
+    }
+
+    //Returns the closest hit given by `ray_cast` on the primitives whose bounds the ray crosses
+    pub fn ray_cast<'a>(
+        &self,
+        ray: &Ray,
+        mut ray_cast: impl FnMut(usize) -> Option<RaycastHit<'a>>,
+    ) -> Option<RaycastHit<'a>> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let inverse_direction = ray.direction.recip();
+        let mut closest_hit: Option<RaycastHit> = None;
+        let mut min_distance = f32::INFINITY;
+
+        let mut stack = [0usize; MAX_DEPTH];
+        let mut stack_size = 1;
+
+        while stack_size > 0 {
+            stack_size -= 1;
+            let node_index = stack[stack_size];
+            let node = &self.nodes[node_index];
+
+            if node
+                .bounds
+                .intersect(ray, inverse_direction, min_distance)
+                .is_none()
+            {
+                continue;
+            }
+
+            if node.is_leaf() {
+                let first = node.first as usize;
+                for &primitive in &self.primitives[first..first + node.count as usize] {
+                    if let Some(hit) = ray_cast(primitive) {
+                        if min_distance > hit.distance {
+                            min_distance = hit.distance;
+                            closest_hit = Some(hit);
+                        }
+                    }
+                }
+            } else {
+                //Visit the nearest child first
+                let left = node_index + 1;
+                let right = node.first as usize;
+
+                let left_distance =
+                    self.nodes[left]
+                        .bounds
+                        .intersect(ray, inverse_direction, min_distance);
+                let right_distance =
+                    self.nodes[right]
+                        .bounds
+                        .intersect(ray, inverse_direction, min_distance);
+
+                let (near, far) = match (left_distance, right_distance) {
+                    (Some(l), Some(r)) if r < l => (Some(right), Some(left)),
+                    (Some(_), Some(_)) => (Some(left), Some(right)),
+                    (Some(_), None) => (Some(left), None),
+                    (None, Some(_)) => (Some(right), None),
+                    (None, None) => (None, None),
+                };
+
+                for child in [far, near].into_iter().flatten() {
+                    stack[stack_size] = child;
+                    stack_size += 1;
+                }
+            }
+        }
+
+        closest_hit
+    }
+}
+
+//Moves the elements matching `predicate` to the front, returns how many there are
+fn partition<T>(items: &mut [T], predicate: impl Fn(&T) -> bool) -> usize {
+    let mut split = 0;
+    for i in 0..items.len() {
+        if predicate(&items[i]) {
+            items.swap(split, i);
+            split += 1;
+        }
+    }
+    split
+}
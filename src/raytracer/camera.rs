@@ -1,4 +1,8 @@
-use glam::{vec3, Vec3};
+use std::f32::consts::{FRAC_PI_2, PI};
+
+use glam::{vec3, Quat, Vec3};
+
+use super::ray::{MediumStack, Ray};
 
 pub struct Resolution {
     pub width: u32,
@@ -23,9 +27,10 @@ pub struct Viewport {
 }
 
 impl Viewport {
-    pub fn new(size: f32, resolution: Resolution, camera: &Camera) -> Self {
+    pub fn new(resolution: Resolution, camera: &Camera) -> Self {
+        let (size, height) = camera.viewport_size(resolution.aspect_ratio());
         let u = camera.right * size;
-        let v = camera.up * -(size / resolution.aspect_ratio());
+        let v = camera.up * -height;
 
         let origin = camera.position + camera.forward * camera.focal_length - u / 2.0 - v / 2.0;
 
@@ -47,30 +52,117 @@ impl Viewport {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct Camera {
     pub position: Vec3,
+    //Width of the viewport, unless `vertical_fov` is set
     pub size: f32,
+    //Radians, keeps the same vertical view whatever the aspect ratio
+    pub vertical_fov: Option<f32>,
     pub focal_length: f32,
+    //Diameter of the lens, 0 for a pinhole where everything is sharp
+    pub aperture: f32,
+    //Distance along `forward` of the plane in focus
+    pub focus_distance: f32,
     pub up: Vec3,
     pub forward: Vec3,
     pub right: Vec3,
+    //Up direction of the world, the camera turns around it and keeps its horizon level with it
+    world_up: Vec3,
+    //Radians, counterclockwise around `forward`
+    roll: f32,
 }
 
-impl Camera {
-    pub fn new(position: Vec3, size: f32, look_at: Vec3, focal_length: f32) -> Self {
-        const GLOBAL_UP: Vec3 = vec3(0.0, 1.0, 0.0);
+const GLOBAL_UP: Vec3 = vec3(0.0, 1.0, 0.0);
 
-        let forward = (look_at - position).normalize();
-        let right = forward.cross(GLOBAL_UP);
-        let up = right.cross(forward);
+//Keeps the camera from flipping over when looking straight up or down
+const MAX_PITCH: f32 = 89.0 * PI / 180.0;
 
-        Self {
+impl Camera {
+    pub fn new(position: Vec3, size: f32, look_at: Vec3, focal_length: f32) -> Self {
+        let mut camera = Self {
             position,
             size,
+            vertical_fov: None,
             focal_length,
-            up,
-            forward,
-            right,
+            aperture: 0.0,
+            focus_distance: (look_at - position).length(),
+            up: GLOBAL_UP,
+            forward: Vec3::NEG_Z,
+            right: Vec3::X,
+            world_up: GLOBAL_UP,
+            roll: 0.0,
+        };
+        camera.look_towards((look_at - position).normalize());
+
+        camera
+    }
+
+    //`up` doesn't need to be perpendicular to the view direction, `roll` is in radians
+    pub fn set_orientation(&mut self, up: Vec3, roll: f32) {
+        self.world_up = up.normalize();
+        self.roll = roll;
+        self.look_towards(self.forward);
+    }
+
+    fn look_towards(&mut self, forward: Vec3) {
+        //The horizon is undefined when looking along the world up, any perpendicular one is used then
+        let right = forward.cross(self.world_up);
+        let right = if right.length_squared() > 1e-12 {
+            right.normalize()
+        } else {
+            forward.any_orthonormal_vector()
+        };
+        let up = right.cross(forward);
+
+        let (sin, cos) = self.roll.sin_cos();
+        self.forward = forward;
+        self.right = cos * right + sin * up;
+        self.up = cos * up - sin * right;
+    }
+
+    //Width and height of the viewport
+    pub fn viewport_size(&self, aspect_ratio: f32) -> (f32, f32) {
+        match self.vertical_fov {
+            Some(fov) => {
+                let height = 2.0 * self.focal_length * (fov / 2.0).tan();
+                (height * aspect_ratio, height)
+            }
+            None => (self.size, self.size / aspect_ratio),
         }
     }
+
+    //Moves by `offset` given along the right, up and forward axes of the camera
+    pub fn translate(&mut self, offset: Vec3) {
+        self.position += offset.x * self.right + offset.y * self.up + offset.z * self.forward;
+    }
+
+    //Turns right by `yaw` and up by `pitch`, in radians
+    pub fn rotate(&mut self, yaw: f32, pitch: f32) {
+        let current_pitch = FRAC_PI_2 - self.forward.angle_between(self.world_up);
+        let pitch = (current_pitch + pitch).clamp(-MAX_PITCH, MAX_PITCH) - current_pitch;
+
+        let horizon = self
+            .forward
+            .cross(self.world_up)
+            .try_normalize()
+            .unwrap_or(self.right);
+        let rotation =
+            Quat::from_axis_angle(self.world_up, -yaw) * Quat::from_axis_angle(horizon, pitch);
+
+        self.look_towards((rotation * self.forward).normalize());
+    }
+
+    //Ray through `viewport_point` from a random point of the lens, focused on the focus plane
+    //Thin lens model [https://pbr-book.org/4ed/Cameras_and_Film/Projective_Camera_Models#TheThinLensModelandDepthofField]
+    pub fn primary_ray(&self, viewport_point: Vec3) -> Ray {
+        let focus_point = self.position
+            + (viewport_point - self.position) * (self.focus_distance / self.focal_length);
+
+        let radius = self.aperture / 2.0 * fastrand::f32().sqrt();
+        let angle = 2.0 * PI * fastrand::f32();
+        let origin = self.position + radius * (angle.cos() * self.right + angle.sin() * self.up);
+
+        Ray::new(origin, focus_point - origin, MediumStack::EMPTY)
+    }
 }
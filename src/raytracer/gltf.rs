@@ -0,0 +1,254 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use glam::{Mat3, Mat4, Vec2, Vec3};
+use gltf::{camera::Projection, material::AlphaMode, mesh::Mode, Document, Gltf, Node};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+
+use super::{bvh::Aabb, camera::Camera, scene::MaterialDescription, shape::Mesh, Rgb};
+
+//Vertical field of view of the camera framing models without cameras
+const DEFAULT_FOV: f32 = 0.7;
+
+//Mesh primitive in world space, `material` indexes `GltfModel::materials`
+pub struct GltfMesh {
+    pub mesh: Mesh,
+    pub material: usize,
+}
+
+pub struct GltfModel {
+    pub meshes: Vec<GltfMesh>,
+    pub materials: Vec<MaterialDescription>,
+    pub camera: Option<Camera>,
+}
+
+impl GltfModel {
+    //Loads the default scene of a `.gltf` or `.glb` file
+    pub fn load(path: &Path) -> Result<Self> {
+        let Gltf { document, blob } = Gltf::open(path)
+            .with_context(|| format!("Failed to read glTF file {}", path.display()))?;
+
+        let directory = path.parent().unwrap_or(Path::new(""));
+        let buffers = gltf::import_buffers(&document, Some(directory), blob)
+            .with_context(|| format!("Failed to read glTF buffers of {}", path.display()))?;
+
+        Self::from_document(&document, &buffers)
+            .with_context(|| format!("Failed to load glTF file {}", path.display()))
+    }
+
+    fn from_document(document: &Document, buffers: &[gltf::buffer::Data]) -> Result<Self> {
+        let Some(scene) = document
+            .default_scene()
+            .or_else(|| document.scenes().next())
+        else {
+            bail!("the file contains no scene");
+        };
+
+        //Primitives without material use the last one
+        let mut materials: Vec<MaterialDescription> = document
+            .materials()
+            .map(|m| describe_material(&m))
+            .collect();
+        let default_material = materials.len();
+        materials.push(MaterialDescription::Diffuse {
+            color: Rgb([255, 255, 255]),
+        });
+
+        let mut model = GltfModel {
+            meshes: Vec::new(),
+            materials,
+            camera: None,
+        };
+
+        //Depth first, in document order
+        let mut nodes: Vec<(Node, Mat4)> =
+            scene.nodes().map(|node| (node, Mat4::IDENTITY)).collect();
+        nodes.reverse();
+        while let Some((node, parent_transform)) = nodes.pop() {
+            let transform = parent_transform * Mat4::from_cols_array_2d(&node.transform().matrix());
+
+            if let Some(mesh) = node.mesh() {
+                for primitive in mesh.primitives() {
+                    let name = mesh.name().unwrap_or("unnamed");
+                    if primitive.mode() != Mode::Triangles {
+                        warn!(
+                            "Skipping primitive {} of mesh \"{name}\", only triangles are supported",
+                            primitive.index()
+                        );
+                        continue;
+                    }
+
+                    let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+                    let Some(positions) = reader.read_positions() else {
+                        bail!(
+                            "primitive {} of mesh \"{name}\" has no positions",
+                            primitive.index()
+                        );
+                    };
+                    let positions: Vec<Vec3> = positions
+                        .map(|position| transform.transform_point3(Vec3::from(position)))
+                        .collect();
+
+                    let normal_transform = Mat3::from_mat4(transform).inverse().transpose();
+                    let normals = reader.read_normals().map(|normals| {
+                        normals
+                            .map(|normal| {
+                                (normal_transform * Vec3::from(normal)).normalize_or_zero()
+                            })
+                            .collect()
+                    });
+                    let uvs = reader
+                        .read_tex_coords(0)
+                        .map(|uvs| uvs.into_f32().map(Vec2::from).collect());
+
+                    let indices: Vec<u32> = match reader.read_indices() {
+                        Some(indices) => indices.into_u32().collect(),
+                        None => (0..positions.len() as u32).collect(),
+                    };
+                    //Mirroring transforms flip the winding, and with it the geometric normals
+                    let mirrored = transform.determinant() < 0.0;
+                    let indices = indices
+                        .chunks_exact(3)
+                        .map(|triangle| match mirrored {
+                            true => [triangle[0], triangle[2], triangle[1]],
+                            false => [triangle[0], triangle[1], triangle[2]],
+                        })
+                        .collect();
+
+                    let mesh = Mesh {
+                        positions,
+                        normals,
+                        uvs,
+                        indices,
+                    };
+                    if let Err((field, message)) = mesh.validate() {
+                        bail!(
+                            "primitive {} of mesh \"{name}\" has invalid {field}: {message}",
+                            primitive.index()
+                        );
+                    }
+
+                    model.meshes.push(GltfMesh {
+                        mesh,
+                        material: primitive.material().index().unwrap_or(default_material),
+                    });
+                }
+            }
+
+            //The first perspective camera found is used
+            if let Some(camera) = node.camera() {
+                match camera.projection() {
+                    Projection::Perspective(perspective) if model.camera.is_none() => {
+                        let position = transform.transform_point3(Vec3::ZERO);
+                        let forward = transform.transform_vector3(Vec3::NEG_Z);
+                        let up = transform.transform_vector3(Vec3::Y);
+
+                        model.camera = Some(perspective_camera(
+                            position,
+                            position + forward,
+                            up,
+                            perspective.yfov(),
+                        ));
+                    }
+                    Projection::Perspective(_) => (),
+                    Projection::Orthographic(_) => {
+                        warn!(
+                            "Skipping camera {}, orthographic cameras are not supported",
+                            camera.index()
+                        )
+                    }
+                }
+            }
+
+            let first_child = nodes.len();
+            nodes.extend(node.children().map(|child| (child, transform)));
+            nodes[first_child..].reverse();
+        }
+
+        Ok(model)
+    }
+
+    //Camera looking at the model slightly from above, for files without cameras
+    pub fn framing_camera(&self) -> Camera {
+        let bounds = self.meshes.iter().fold(Aabb::EMPTY, |acc, mesh| {
+            acc.union(&mesh.mesh.bounding_box())
+        });
+
+        let (center, radius) = if self.meshes.is_empty() {
+            (Vec3::ZERO, 1.0)
+        } else {
+            let diagonal = (bounds.max - bounds.min).length();
+            (bounds.centroid(), diagonal.max(f32::EPSILON) / 2.0)
+        };
+        let distance = radius / (DEFAULT_FOV / 2.0).sin();
+
+        perspective_camera(
+            center + Vec3::new(0.0, 0.5, 1.0).normalize() * distance,
+            center,
+            Vec3::Y,
+            DEFAULT_FOV,
+        )
+    }
+}
+
+//Keeps the vertical field of view of the file whatever the aspect ratio of the render
+fn perspective_camera(position: Vec3, look_at: Vec3, up: Vec3, vertical_fov: f32) -> Camera {
+    //`size` is unused once the field of view is set
+    let mut camera = Camera::new(position, 1.0, look_at, 1.0);
+    camera.set_orientation(up, 0.0);
+    camera.vertical_fov = Some(vertical_fov);
+
+    camera
+}
+
+//Inverts the color `KHR_materials_volume` reaches at the attenuation distance
+fn volume_absorption(material: &gltf::Material) -> Vec3 {
+    let Some(volume) = material.volume() else {
+        return Vec3::ZERO;
+    };
+
+    //The default infinite distance means no absorption
+    let distance = volume.attenuation_distance();
+    Vec3::from(
+        volume
+            .attenuation_color()
+            .map(|c| -c.clamp(1e-6, 1.0).ln() / distance),
+    )
+}
+
+//Maps emission, mostly transmissive volumes and the metallic-roughness model onto raytracer materials
+fn describe_material(material: &gltf::Material) -> MaterialDescription {
+    let pbr = material.pbr_metallic_roughness();
+    let [r, g, b, alpha] = pbr.base_color_factor();
+    let color = Rgb([r, g, b].map(|c| (c.clamp(0.0, 1.0) * 255.0) as u8));
+
+    let transmission = material
+        .transmission()
+        .map_or(0.0, |transmission| transmission.transmission_factor());
+    let blended = material.alpha_mode() == AlphaMode::Blend && alpha < 1.0;
+    let emission =
+        Vec3::from(material.emissive_factor()) * material.emissive_strength().unwrap_or(1.0);
+
+    if emission.max_element() > 0.0 {
+        MaterialDescription::emissive(emission)
+    } else if transmission >= 0.5 || blended {
+        MaterialDescription::Transparent {
+            color,
+            ior: material.ior().unwrap_or(1.5),
+            priority: 0,
+            absorption: volume_absorption(material),
+        }
+    } else {
+        MaterialDescription::Principled {
+            color,
+            metallic: pbr.metallic_factor().clamp(0.0, 1.0),
+            roughness: pbr.roughness_factor().clamp(0.0, 1.0),
+            specular: 0.5,
+            transmission: transmission.clamp(0.0, 1.0),
+            ior: material.ior().unwrap_or(1.5),
+            clearcoat: 0.0,
+            sheen: 0.0,
+        }
+    }
+}
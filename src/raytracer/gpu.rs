@@ -0,0 +1,841 @@
+use std::{sync::mpsc, time::SystemTime};
+
+use anyhow::{bail, Context, Result};
+use bytemuck::{Pod, Zeroable};
+use glam::Vec3;
+#[allow(unused_imports)]
+use log::{info, warn};
+use wgpu::util::DeviceExt;
+
+use super::{
+    bvh::Bvh,
+    camera::Camera,
+    scene::{MaterialDescription, Scene, Shape},
+    shape::triangle_bounding_box,
+    Rgb, Rgba, Texture,
+};
+
+pub const OUTPUT_TEXTURE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+
+//Side of the square compute workgroups, lowered when the adapter can't run that many invocations
+pub const DEFAULT_WORKGROUP_SIZE: u32 = 16;
+//Offscreen renders are split into dispatches of at most this many samples
+const MAX_SAMPLES_PER_DISPATCH: u32 = 64;
+
+//Bytes per pixel of the accumulation buffer
+const ACCUMULATION_PIXEL_SIZE: wgpu::BufferAddress =
+    std::mem::size_of::<[f32; 4]>() as wgpu::BufferAddress;
+
+const COMPUTE_SHADER: &str = include_str!("../shaders/compute/shader.wgsl");
+
+//Layouts mirrored by the structs of shaders/compute/shader.wgsl
+pub const MAT_TYPE_DIFFUSE: u32 = 1 << 0;
+pub const MAT_TYPE_METALIC: u32 = 1 << 1;
+pub const MAT_TYPE_TRANSPARENT: u32 = 1 << 2;
+pub const MAT_TYPE_EMISSIVE: u32 = 1 << 3;
+pub const MAT_TYPE_PRINCIPLED: u32 = 1 << 4;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct GpuCamera {
+    position: [f32; 3],
+    size: f32,
+    forward: [f32; 3],
+    focal_length: f32,
+    right: [f32; 3],
+    aperture: f32,
+    up: [f32; 3],
+    focus_distance: f32,
+    //Radians, 0 when `size` is used instead
+    vertical_fov: f32,
+    _padding: [u32; 3],
+}
+
+impl From<&Camera> for GpuCamera {
+    fn from(camera: &Camera) -> Self {
+        Self {
+            position: camera.position.into(),
+            size: camera.size,
+            forward: camera.forward.into(),
+            focal_length: camera.focal_length,
+            right: camera.right.into(),
+            aperture: camera.aperture,
+            up: camera.up.into(),
+            focus_distance: camera.focus_distance,
+            vertical_fov: camera.vertical_fov.unwrap_or(0.0),
+            _padding: [0; 3],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct GpuMaterial {
+    color: [f32; 3],
+    mat_type: u32,
+    emission: [f32; 3],
+    ior: f32,
+    absorption: [f32; 3],
+    roughness: f32,
+    metallic: f32,
+    specular: f32,
+    transmission: f32,
+    clearcoat: f32,
+    sheen: f32,
+    _padding: [u32; 3],
+}
+
+impl From<&MaterialDescription> for GpuMaterial {
+    fn from(material: &MaterialDescription) -> Self {
+        let to_f32 = |color: Rgb<u8>| color.map(|c| c as f32 / 255.0);
+        let base = |mat_type, color| Self {
+            color: to_f32(color),
+            mat_type,
+            ior: 1.0,
+            roughness: 1.0,
+            ..Self::zeroed()
+        };
+
+        match *material {
+            MaterialDescription::Diffuse { color } => base(MAT_TYPE_DIFFUSE, color),
+            MaterialDescription::Metal { color, roughness } => Self {
+                roughness,
+                ..base(MAT_TYPE_METALIC, color)
+            },
+            MaterialDescription::Transparent {
+                color,
+                ior,
+                absorption,
+                ..
+            } => Self {
+                ior,
+                absorption: absorption.into(),
+                roughness: 0.0,
+                ..base(MAT_TYPE_TRANSPARENT, color)
+            },
+            MaterialDescription::Emissive { color, strength } => Self {
+                emission: to_f32(color).map(|c| c * strength),
+                ..base(MAT_TYPE_EMISSIVE, color)
+            },
+            MaterialDescription::Principled {
+                color,
+                metallic,
+                roughness,
+                specular,
+                transmission,
+                ior,
+                clearcoat,
+                sheen,
+            } => Self {
+                ior,
+                roughness,
+                metallic,
+                specular,
+                transmission,
+                clearcoat,
+                sheen,
+                ..base(MAT_TYPE_PRINCIPLED, color)
+            },
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct GpuSphere {
+    position: [f32; 3],
+    radius: f32,
+    material: u32,
+    _padding: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct GpuTriangle {
+    vertices: [[f32; 4]; 3],
+    normals: [[f32; 4]; 3],
+    material: u32,
+    _padding: [u32; 3],
+}
+
+impl GpuTriangle {
+    //Without vertex normals, the geometric normal is used for the whole face
+    fn new(vertices: [Vec3; 3], normals: Option<[Vec3; 3]>, material: usize) -> Self {
+        let normals = normals.unwrap_or_else(|| {
+            let normal = (vertices[1] - vertices[0])
+                .cross(vertices[2] - vertices[0])
+                .normalize_or_zero();
+            [normal; 3]
+        });
+
+        Self {
+            vertices: vertices.map(|v| v.extend(0.0).into()),
+            normals: normals.map(|n| n.extend(0.0).into()),
+            material: material as u32,
+            _padding: [0; 3],
+        }
+    }
+}
+
+pub const PRIMITIVE_SPHERE: u32 = 0;
+pub const PRIMITIVE_TRIANGLE: u32 = 1;
+
+//Reference to a sphere or a triangle, in BVH order
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct GpuPrimitive {
+    kind: u32,
+    index: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct GpuBvhNode {
+    min: [f32; 3],
+    skip: u32,
+    max: [f32; 3],
+    first: u32,
+    count: u32,
+    _padding: [u32; 3],
+}
+
+enum Primitive {
+    Sphere(GpuSphere),
+    Triangle(GpuTriangle),
+}
+
+//Scene flattened into the storage buffers read by the compute shader,
+//meshes are split into triangles and primitives are ordered so that BVH leaves reference contiguous ranges
+pub struct GpuScene {
+    pub camera: GpuCamera,
+    pub materials: Vec<GpuMaterial>,
+    pub spheres: Vec<GpuSphere>,
+    pub triangles: Vec<GpuTriangle>,
+    pub primitives: Vec<GpuPrimitive>,
+    pub bvh_nodes: Vec<GpuBvhNode>,
+    pub bvh_node_count: u32,
+}
+
+impl GpuScene {
+    pub fn new(scene: &Scene) -> Self {
+        let mut bounds = Vec::with_capacity(scene.objects.len());
+        let mut scene_primitives = Vec::with_capacity(scene.objects.len());
+        for object in &scene.objects {
+            match &object.shape {
+                Shape::Sphere { position, radius } => {
+                    bounds.push(object.shape.bounding_box());
+                    scene_primitives.push(Primitive::Sphere(GpuSphere {
+                        position: (*position).into(),
+                        radius: *radius,
+                        material: object.material as u32,
+                        _padding: [0; 3],
+                    }));
+                }
+                Shape::Triangle {
+                    vertices, normals, ..
+                } => {
+                    bounds.push(object.shape.bounding_box());
+                    scene_primitives.push(Primitive::Triangle(GpuTriangle::new(
+                        *vertices,
+                        *normals,
+                        object.material,
+                    )));
+                }
+                Shape::Mesh(mesh) => {
+                    for i in 0..mesh.triangle_count() {
+                        let vertices = mesh.vertices(i);
+                        bounds.push(triangle_bounding_box(&vertices));
+                        scene_primitives.push(Primitive::Triangle(GpuTriangle::new(
+                            vertices,
+                            mesh.vertex_normals(i),
+                            object.material,
+                        )));
+                    }
+                }
+            }
+        }
+
+        let bvh = Bvh::new(&bounds);
+
+        let mut spheres = Vec::new();
+        let mut triangles = Vec::new();
+        let mut primitives: Vec<GpuPrimitive> = bvh
+            .primitives
+            .iter()
+            .map(|&i| match scene_primitives[i] {
+                Primitive::Sphere(sphere) => {
+                    spheres.push(sphere);
+                    GpuPrimitive {
+                        kind: PRIMITIVE_SPHERE,
+                        index: spheres.len() as u32 - 1,
+                    }
+                }
+                Primitive::Triangle(triangle) => {
+                    triangles.push(triangle);
+                    GpuPrimitive {
+                        kind: PRIMITIVE_TRIANGLE,
+                        index: triangles.len() as u32 - 1,
+                    }
+                }
+            })
+            .collect();
+
+        let mut bvh_nodes: Vec<GpuBvhNode> = bvh
+            .nodes
+            .iter()
+            .zip(bvh.skip_indices())
+            .map(|(node, skip)| GpuBvhNode {
+                min: node.bounds.min.into(),
+                skip,
+                max: node.bounds.max.into(),
+                first: if node.is_leaf() { node.first } else { 0 },
+                count: node.count,
+                _padding: [0; 3],
+            })
+            .collect();
+        let bvh_node_count = bvh_nodes.len() as u32;
+
+        let mut materials: Vec<GpuMaterial> =
+            scene.materials.iter().map(GpuMaterial::from).collect();
+        if scene.materials.iter().any(|material| {
+            matches!(material, MaterialDescription::Transparent { priority, .. } if *priority != 0)
+        }) {
+            warn!("Transparent priorities are ignored on the GPU, every volume is assumed to be surrounded by air");
+        }
+
+        //Storage buffers cannot be empty, the shader only traverses the first `bvh_node_count` nodes
+        if bvh_nodes.is_empty() {
+            bvh_nodes.push(GpuBvhNode::zeroed());
+        }
+        if primitives.is_empty() {
+            primitives.push(GpuPrimitive::zeroed());
+        }
+        if spheres.is_empty() {
+            spheres.push(GpuSphere::zeroed());
+        }
+        if triangles.is_empty() {
+            triangles.push(GpuTriangle::zeroed());
+        }
+        if materials.is_empty() {
+            materials.push(GpuMaterial::zeroed());
+        }
+
+        Self {
+            camera: GpuCamera::from(&scene.camera),
+            materials,
+            spheres,
+            triangles,
+            primitives,
+            bvh_nodes,
+            bvh_node_count,
+        }
+    }
+}
+
+//Compute pipeline and scene buffers, shared by the window and offscreen rendering
+pub struct GpuRaytracer {
+    pipeline: wgpu::ComputePipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    parameters_buffer: wgpu::Buffer,
+    camera_buffer: wgpu::Buffer,
+    materials_buffer: wgpu::Buffer,
+    spheres_buffer: wgpu::Buffer,
+    triangles_buffer: wgpu::Buffer,
+    primitives_buffer: wgpu::Buffer,
+    bvh_buffer: wgpu::Buffer,
+    bvh_node_count: u32,
+    workgroup_size: u32,
+    //Samples averaged since the last reset
+    accumulated_samples: u32,
+}
+
+impl GpuRaytracer {
+    //`workgroup_size` defaults to `DEFAULT_WORKGROUP_SIZE`, both are lowered to what the adapter can run
+    pub fn new(device: &wgpu::Device, scene: &Scene, workgroup_size: Option<u32>) -> Result<Self> {
+        let limits = device.limits();
+        let max_workgroup_size = limits
+            .max_compute_workgroup_size_x
+            .min(limits.max_compute_workgroup_size_y)
+            .min((limits.max_compute_invocations_per_workgroup as f64).sqrt() as u32);
+        let workgroup_size = match workgroup_size {
+            Some(size) if size > max_workgroup_size => {
+                warn!("Workgroup size {size} is too large for this adapter, using {max_workgroup_size}");
+                max_workgroup_size
+            }
+            Some(size) => size,
+            None => DEFAULT_WORKGROUP_SIZE.min(max_workgroup_size),
+        };
+        info!("Using {workgroup_size}x{workgroup_size} compute workgroups");
+
+        //Overrides can't size workgroups with this version of wgpu, the constant is replaced instead
+        let default_declaration = format!("const WORKGROUP_SIZE: u32 = {DEFAULT_WORKGROUP_SIZE}u;");
+        if !COMPUTE_SHADER.contains(&default_declaration) {
+            bail!("The compute shader doesn't declare `{default_declaration}`, its workgroup size can't be set");
+        }
+        let source = COMPUTE_SHADER.replacen(
+            &default_declaration,
+            &format!("const WORKGROUP_SIZE: u32 = {workgroup_size}u;"),
+            1,
+        );
+        let shader_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Compute shader"),
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+
+        //Bind group layout
+        let uniform_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let storage_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Compute bind group layout"),
+            entries: &[
+                uniform_entry(0),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::StorageTexture {
+                        access: wgpu::StorageTextureAccess::WriteOnly,
+                        format: OUTPUT_TEXTURE_FORMAT,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                uniform_entry(2),
+                storage_entry(3),
+                storage_entry(4),
+                storage_entry(5),
+                storage_entry(6),
+                storage_entry(7),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 8,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        //Buffers
+        let gpu_scene = GpuScene::new(scene);
+
+        let parameters_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Compute parameters buffer"),
+            size: std::mem::size_of::<[u32; 8]>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
+            mapped_at_creation: false,
+        });
+
+        let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Camera Buffer"),
+            contents: bytemuck::bytes_of(&gpu_scene.camera),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
+        });
+
+        let materials_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Materials Buffer"),
+            contents: bytemuck::cast_slice(&gpu_scene.materials),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+        });
+
+        let spheres_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Spheres Buffer"),
+            contents: bytemuck::cast_slice(&gpu_scene.spheres),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+        });
+
+        let triangles_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Triangles Buffer"),
+            contents: bytemuck::cast_slice(&gpu_scene.triangles),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+        });
+
+        let primitives_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Primitives Buffer"),
+            contents: bytemuck::cast_slice(&gpu_scene.primitives),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+        });
+
+        let bvh_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("BVH Buffer"),
+            contents: bytemuck::cast_slice(&gpu_scene.bvh_nodes),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+        });
+
+        //Pipeline
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Compute pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Compute pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader_module,
+            entry_point: "main",
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        Ok(Self {
+            pipeline,
+            bind_group_layout,
+            parameters_buffer,
+            camera_buffer,
+            materials_buffer,
+            spheres_buffer,
+            triangles_buffer,
+            primitives_buffer,
+            bvh_buffer,
+            bvh_node_count: gpu_scene.bvh_node_count,
+            workgroup_size,
+            accumulated_samples: 0,
+        })
+    }
+
+    //Running average of the samples of each pixel, as `rgba32float`
+    pub fn create_accumulation_buffer(
+        &self,
+        device: &wgpu::Device,
+        (width, height): (u32, u32),
+    ) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Accumulation buffer"),
+            size: width as wgpu::BufferAddress
+                * height as wgpu::BufferAddress
+                * ACCUMULATION_PIXEL_SIZE,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        })
+    }
+
+    //`output` must be a view of an `OUTPUT_TEXTURE_FORMAT` texture with `STORAGE_BINDING` usage
+    //`accumulation` must come from `create_accumulation_buffer` with the same size as `output`
+    pub fn create_bind_group(
+        &self,
+        device: &wgpu::Device,
+        output: &wgpu::TextureView,
+        accumulation: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Compute bind group"),
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: self.parameters_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(output),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: self.camera_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: self.materials_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: self.spheres_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: self.bvh_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: self.triangles_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: self.primitives_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: accumulation.as_entire_binding(),
+                },
+            ],
+        })
+    }
+
+    //Starts averaging from scratch on the next dispatch, needed once the image changes
+    pub fn reset_accumulation(&mut self) {
+        self.accumulated_samples = 0;
+    }
+
+    pub fn update_camera(&mut self, queue: &wgpu::Queue, camera: &Camera) {
+        queue.write_buffer(
+            &self.camera_buffer,
+            0,
+            bytemuck::bytes_of(&GpuCamera::from(camera)),
+        );
+        self.reset_accumulation();
+    }
+
+    //Renders `sample_count` more samples per pixel, averaged with the previous dispatches
+    pub fn dispatch(
+        &mut self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        bind_group: &wgpu::BindGroup,
+        (width, height): (u32, u32),
+        sample_count: u32,
+        max_bounces: u32,
+    ) {
+        //Uniform structs are padded to 16 bytes
+        let parameters = [
+            width,
+            height,
+            sample_count,
+            self.bvh_node_count,
+            max_bounces,
+            self.accumulated_samples,
+            0,
+            0,
+        ];
+        queue.write_buffer(
+            &self.parameters_buffer,
+            0,
+            bytemuck::cast_slice(&parameters),
+        );
+
+        let mut compute_pass = encoder.begin_compute_pass(&Default::default());
+        compute_pass.set_pipeline(&self.pipeline);
+        compute_pass.set_bind_group(0, bind_group, &[]);
+        compute_pass.dispatch_workgroups(
+            width.div_ceil(self.workgroup_size),
+            height.div_ceil(self.workgroup_size),
+            1,
+        );
+
+        self.accumulated_samples += sample_count;
+    }
+}
+
+//Most pixels the accumulation buffer can hold within `limits`
+pub fn max_pixel_count(limits: &wgpu::Limits) -> u64 {
+    let max_size = limits
+        .max_buffer_size
+        .min(limits.max_storage_buffer_binding_size as u64);
+    max_size / ACCUMULATION_PIXEL_SIZE
+}
+
+//Shrinks a size until its output texture and accumulation buffer fit within `limits`
+pub fn fit_resolution(limits: &wgpu::Limits, (width, height): (u32, u32)) -> (u32, u32) {
+    let max_side = limits.max_texture_dimension_2d;
+    let (width, height) = (width.min(max_side), height.min(max_side));
+
+    let pixel_count = width as u64 * height as u64;
+    let max_pixel_count = max_pixel_count(limits);
+    if pixel_count <= max_pixel_count {
+        return (width, height);
+    }
+
+    //Keeps the aspect ratio, rounding down so that the product stays within the limit
+    let scale = (max_pixel_count as f64 / pixel_count as f64).sqrt();
+    (
+        ((width as f64 * scale) as u32).max(1),
+        ((height as f64 * scale) as u32).max(1),
+    )
+}
+
+//Prefers a hardware adapter, falling back to a software one (e.g. for CI)
+pub async fn request_device(
+    instance: &wgpu::Instance,
+    compatible_surface: Option<&wgpu::Surface<'_>>,
+) -> Result<(wgpu::Adapter, wgpu::Device, wgpu::Queue)> {
+    let mut options = wgpu::RequestAdapterOptions {
+        power_preference: wgpu::PowerPreference::HighPerformance,
+        force_fallback_adapter: false,
+        compatible_surface,
+    };
+
+    let adapter = match instance.request_adapter(&options).await {
+        Some(adapter) => adapter,
+        None => {
+            warn!("No hardware adapter found, trying a fallback adapter");
+            options.force_fallback_adapter = true;
+            instance
+                .request_adapter(&options)
+                .await
+                .context("Failed to get adapter")?
+        }
+    };
+    info!("{:?}", adapter.get_info());
+
+    //The adapter's own limits allow larger images than the defaults
+    let (device, queue) = adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                required_limits: adapter.limits(),
+                ..Default::default()
+            },
+            None,
+        )
+        .await?;
+
+    Ok((adapter, device, queue))
+}
+
+//Renders the scene without any window and reads the result back into `texture`
+pub async fn render_offscreen(
+    texture: &mut Texture,
+    scene: &Scene,
+    sample_count: u32,
+    max_bounces: u32,
+    workgroup_size: Option<u32>,
+) -> Result<()> {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::Backends::all(),
+        ..Default::default()
+    });
+    let (_, device, queue) = request_device(&instance, None).await?;
+
+    let (width, height) = (texture.width as u32, texture.height as u32);
+    let size = wgpu::Extent3d {
+        width,
+        height,
+        depth_or_array_layers: 1,
+    };
+
+    let output_texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Offscreen output texture"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: OUTPUT_TEXTURE_FORMAT,
+        usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[],
+    });
+    let output_view = output_texture.create_view(&Default::default());
+
+    //Rows of a texture to buffer copy must be aligned
+    let unpadded_bytes_per_row = width * std::mem::size_of::<Rgba<u8>>() as u32;
+    let bytes_per_row = unpadded_bytes_per_row.next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+
+    let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Readback buffer"),
+        size: (bytes_per_row * height) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut raytracer = GpuRaytracer::new(&device, scene, workgroup_size)?;
+    let accumulation_buffer = raytracer.create_accumulation_buffer(&device, (width, height));
+    let bind_group = raytracer.create_bind_group(&device, &output_view, &accumulation_buffer);
+
+    info!("Starting GPU Rendering...");
+    let start_time = SystemTime::now();
+
+    //Each dispatch is submitted on its own so that none runs long enough to be cut short
+    let mut remaining_samples = sample_count;
+    while remaining_samples > 0 {
+        let samples = remaining_samples.min(MAX_SAMPLES_PER_DISPATCH);
+        remaining_samples -= samples;
+
+        let mut encoder = device.create_command_encoder(&Default::default());
+        raytracer.dispatch(
+            &queue,
+            &mut encoder,
+            &bind_group,
+            (width, height),
+            samples,
+            max_bounces,
+        );
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    let mut encoder = device.create_command_encoder(&Default::default());
+    encoder.copy_texture_to_buffer(
+        output_texture.as_image_copy(),
+        wgpu::ImageCopyBuffer {
+            buffer: &readback_buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(bytes_per_row),
+                rows_per_image: Some(height),
+            },
+        },
+        size,
+    );
+    queue.submit(std::iter::once(encoder.finish()));
+
+    //Read back
+    let readback_slice = readback_buffer.slice(..);
+    let (sender, receiver) = mpsc::channel();
+    readback_slice.map_async(wgpu::MapMode::Read, move |result| {
+        let _ = sender.send(result);
+    });
+    device.poll(wgpu::Maintain::Wait).panic_on_timeout();
+    receiver
+        .recv()
+        .context("Readback buffer mapping was dropped")?
+        .context("Failed to map readback buffer")?;
+
+    let pixels = {
+        let data = readback_slice.get_mapped_range();
+        data.chunks_exact(bytes_per_row as usize)
+            .flat_map(|row| {
+                bytemuck::cast_slice::<u8, Rgba<u8>>(&row[..unpadded_bytes_per_row as usize])
+            })
+            .copied()
+            .collect()
+    };
+    readback_buffer.unmap();
+
+    match start_time.elapsed() {
+        Ok(elapsed) => info!("Finished rendering in {}ms", elapsed.as_millis()),
+        Err(e) => {
+            info!("Finished rendering");
+            warn!("Failed to get rendertime {e}");
+        }
+    }
+
+    *texture = Texture::new(pixels, texture.width, texture.height);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fit_resolution_stays_within_limits() {
+        let limits = wgpu::Limits::default();
+        assert_eq!(fit_resolution(&limits, (1920, 1080)), (1920, 1080));
+
+        for size in [(4096, 4096), (7680, 4320), (8192, 100), (20000, 20000)] {
+            let (width, height) = fit_resolution(&limits, size);
+            assert!(width <= limits.max_texture_dimension_2d);
+            assert!(height <= limits.max_texture_dimension_2d);
+            assert!(width as u64 * height as u64 <= max_pixel_count(&limits));
+        }
+    }
+}
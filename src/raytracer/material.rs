@@ -1,12 +1,18 @@
-use super::{ray::Ray, RayBounce};
+use super::{
+    ray::{Medium, Ray},
+    Bounce, BounceEvaluation, RayBounce,
+};
 use crate::raytracer::Rgb;
 
+use std::f32::consts::PI;
+
 use glam::{vec3, Vec3};
 
 //Matrial
 pub trait Material {
     fn get_color(&self) -> Rgb<u8>;
     fn get_ior(&self) -> Option<f32>;
+    fn emitted(&self) -> Rgb<f32>;
 }
 
 pub trait RaytraceMaterial: RayBounce + Material {}
@@ -27,26 +33,175 @@ trait Refract {
 }
 
 // Formula from [https://en.wikipedia.org/wiki/Snell%27s_law]
+//`normal` faces the incident side, `r` is the ratio of the incident over the transmitted ior
 impl Refract for Vec3 {
     fn refract(self, normal: &Vec3, r: f32) -> Self {
-        let c = -normal.dot(self);
-        let c_sq = c * c;
-        let r_sq = r * r;
-
-        let p_1 = r * self;
-        let p_2 = r * c;
-        let p_3 = (1.0 - r_sq * (1.0 - c_sq)).sqrt();
+        let incident = self.normalize_or_zero();
 
-        let sin_a = (1.0 - c * c).sqrt();
+        let c = -normal.dot(incident);
+        let k = 1.0 - r * r * (1.0 - c * c);
 
-        if r * sin_a > 1.0 {
-            self.reflect(normal)
+        //Total internal reflection
+        if k < 0.0 {
+            incident.reflect(normal)
         } else {
-            p_1 + (p_2 - p_3) * (*normal)
+            r * incident + (r * c - k.sqrt()) * (*normal)
         }
     }
 }
 
+//Exact Fresnel reflectance of unpolarized light on a dielectric
+//Formula from [https://en.wikipedia.org/wiki/Fresnel_equations]
+fn fresnel_reflectance(cos_incident: f32, r: f32) -> f32 {
+    let sin_transmitted_sq = r * r * (1.0 - cos_incident * cos_incident);
+    if sin_transmitted_sq >= 1.0 {
+        return 1.0;
+    }
+    let cos_transmitted = (1.0 - sin_transmitted_sq).sqrt();
+
+    let r_s = (r * cos_incident - cos_transmitted) / (r * cos_incident + cos_transmitted);
+    let r_p = (cos_incident - r * cos_transmitted) / (cos_incident + r * cos_transmitted);
+
+    0.5 * (r_s * r_s + r_p * r_p)
+}
+
+//Schlick's approximation of the Fresnel reflectance, `f0` is the reflectance at normal incidence
+//Formula from [https://en.wikipedia.org/wiki/Schlick%27s_approximation]
+fn schlick_weight(cos_incident: f32) -> f32 {
+    (1.0 - cos_incident.clamp(0.0, 1.0)).powi(5)
+}
+
+fn schlick_reflectance(f0: Rgb<f32>, cos_incident: f32) -> Rgb<f32> {
+    let k = schlick_weight(cos_incident);
+    f0 * (1.0 - k) + Rgb::<f32>::WHITE * k
+}
+
+//GGX/Trowbridge-Reitz microfacet distribution, `alpha` is the squared roughness
+//Formulas from [https://jcgt.org/published/0003/02/03/]
+fn ggx_distribution(cos_half: f32, alpha: f32) -> f32 {
+    let alpha_sq = alpha * alpha;
+    let d = cos_half * cos_half * (alpha_sq - 1.0) + 1.0;
+    alpha_sq / (PI * d * d)
+}
+
+//Smith masking, `G1 = 1 / (1 + lambda)`
+fn ggx_lambda(cos: f32, alpha: f32) -> f32 {
+    let cos_sq = cos * cos;
+    let tan_sq = (1.0 - cos_sq).max(0.0) / cos_sq;
+    ((1.0 + alpha * alpha * tan_sq).sqrt() - 1.0) / 2.0
+}
+
+//`D * G2 / (4 * cos_view)`, the reflection without Fresnel times the outgoing cosine,
+//and the pdf of sampling it with `sample_ggx_visible_normal`
+fn ggx_reflection(cos_view: f32, cos_out: f32, cos_half: f32, alpha: f32) -> (f32, f32) {
+    let distribution = ggx_distribution(cos_half, alpha);
+    let lambda_view = ggx_lambda(cos_view, alpha);
+    let lambda_out = ggx_lambda(cos_out, alpha);
+
+    let value = distribution / ((1.0 + lambda_view + lambda_out) * 4.0 * cos_view);
+    let pdf = distribution / ((1.0 + lambda_view) * 4.0 * cos_view);
+    (value, pdf)
+}
+
+//Samples a microfacet normal around `normal` among the ones seen from `view`
+//Algorithm from [https://jcgt.org/published/0007/04/01/]
+fn sample_ggx_visible_normal(view: Vec3, normal: Vec3, alpha: f32) -> Vec3 {
+    let (tangent, bitangent) = normal.any_orthonormal_pair();
+    let view = vec3(
+        alpha * view.dot(tangent),
+        alpha * view.dot(bitangent),
+        view.dot(normal),
+    )
+    .normalize();
+
+    let length_sq = view.x * view.x + view.y * view.y;
+    let t1 = if length_sq > 0.0 {
+        vec3(-view.y, view.x, 0.0) / length_sq.sqrt()
+    } else {
+        Vec3::X
+    };
+    let t2 = view.cross(t1);
+
+    let r = fastrand::f32().sqrt();
+    let phi = 2.0 * PI * fastrand::f32();
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1.0 + view.z);
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+
+    let half = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * view;
+    (alpha * half.x * tangent + alpha * half.y * bitangent + half.z.max(0.0) * normal).normalize()
+}
+
+//Direction around `normal` with a pdf of `cos / PI`
+fn sample_cosine_direction(normal: Vec3) -> Vec3 {
+    let (tangent, bitangent) = normal.any_orthonormal_pair();
+
+    let r_sq = fastrand::f32();
+    let r = r_sq.sqrt();
+    let phi = 2.0 * PI * fastrand::f32();
+
+    r * phi.cos() * tangent + r * phi.sin() * bitangent + (1.0 - r_sq).sqrt() * normal
+}
+
+//Reflects or refracts through the boundary of `medium`, with the probability given by the Fresnel equations
+//Boundaries inside a higher priority volume are crossed without changing medium
+fn dielectric_bounce(
+    medium: Medium,
+    color: Rgb<f32>,
+    ray: &Ray,
+    normal: &Vec3,
+    position: &Vec3,
+) -> Bounce {
+    let position = *position;
+    let incident = ray.direction.normalize_or_zero();
+    let normal = normal.normalize_or_zero();
+
+    let is_inside = incident.dot(normal) > 0.0;
+    let (normal, outside_media, inside_media) = if is_inside {
+        //Rays starting inside the volume never entered it
+        let inside_media = match ray.media.contains(medium.id) {
+            true => ray.media,
+            false => ray.media.with(medium),
+        };
+        (-normal, ray.media.without(medium.id), inside_media)
+    } else {
+        (normal, ray.media, ray.media.with(medium))
+    };
+
+    if inside_media.current() != Some(&medium) {
+        let media = if is_inside {
+            outside_media
+        } else {
+            inside_media
+        };
+
+        return Bounce {
+            ray: Ray::new(position, ray.direction, media),
+            attenuation: Rgb::<f32>::WHITE,
+            pdf: None,
+        };
+    }
+
+    let (ior_ratio, transmitted_media) = if is_inside {
+        (medium.ior / outside_media.ior(), outside_media)
+    } else {
+        (outside_media.ior() / medium.ior, inside_media)
+    };
+
+    let cos_incident = -incident.dot(normal).clamp(-1.0, 1.0);
+    let (direction, media) = if fastrand::f32() < fresnel_reflectance(cos_incident, ior_ratio) {
+        (incident.reflect(&normal), ray.media)
+    } else {
+        (incident.refract(&normal, ior_ratio), transmitted_media)
+    };
+
+    Bounce {
+        ray: Ray::new(position, direction, media),
+        attenuation: color,
+        pdf: None,
+    }
+}
+
 //Diffuse
 pub struct DiffuseMaterial {
     pub color: Rgb<u8>,
@@ -62,21 +217,50 @@ impl Material for DiffuseMaterial {
     fn get_ior(&self) -> Option<f32> {
         None
     }
+
+    fn emitted(&self) -> Rgb<f32> {
+        Rgb::<f32>::BLACK
+    }
 }
 
 impl RayBounce for DiffuseMaterial {
-    fn ray_bounce(&self, _incident: &Vec3, normal: &Vec3, position: &Vec3) -> Option<Ray> {
-        let out = vec3(
-            fastrand::f32() * 2.0 - 1.0,
-            fastrand::f32() * 2.0 - 1.0,
-            fastrand::f32() * 2.0 - 1.0,
-        )
-        .normalize_or_zero();
-
-        Some(Ray {
-            origin: *position,
-            direction: *normal + out,
-            ior: 1.0,
+    fn ray_bounce(&self, ray: &Ray, normal: &Vec3, position: &Vec3) -> Option<Bounce> {
+        //Scatter on the side the ray comes from
+        let normal = normal.normalize_or_zero();
+        let normal = if ray.direction.dot(normal) > 0.0 {
+            -normal
+        } else {
+            normal
+        };
+
+        let direction = sample_cosine_direction(normal);
+
+        //The cosine and the pdf cancel out with the 1/PI of the BRDF
+        Some(Bounce {
+            ray: Ray::new(*position, direction, ray.media),
+            attenuation: self.color.into(),
+            pdf: Some(normal.dot(direction).max(0.0) / PI),
+        })
+    }
+
+    //Lambertian reflection, importance sampled along the cosine
+    fn evaluate_bounce(
+        &self,
+        incident: &Vec3,
+        normal: &Vec3,
+        direction: &Vec3,
+    ) -> Option<BounceEvaluation> {
+        let normal = if incident.dot(*normal) > 0.0 {
+            -*normal
+        } else {
+            *normal
+        };
+
+        let pdf = normal.dot(direction.normalize_or_zero()).max(0.0) / PI;
+
+        Some(BounceEvaluation {
+            value: Rgb::<f32>::from(self.color) * pdf,
+            pdf,
         })
     }
 }
@@ -84,6 +268,14 @@ impl RayBounce for DiffuseMaterial {
 //Metal
 pub struct MetalMaterial {
     pub color: Rgb<u8>,
+    //0 is a perfect mirror
+    pub roughness: f32,
+}
+
+impl MetalMaterial {
+    fn alpha(&self) -> f32 {
+        (self.roughness * self.roughness).max(1e-4)
+    }
 }
 
 impl RaytraceMaterial for MetalMaterial {}
@@ -96,24 +288,109 @@ impl Material for MetalMaterial {
     fn get_ior(&self) -> Option<f32> {
         None
     }
+
+    fn emitted(&self) -> Rgb<f32> {
+        Rgb::<f32>::BLACK
+    }
 }
 
 impl RayBounce for MetalMaterial {
-    fn ray_bounce(&self, incident: &Vec3, normal: &Vec3, position: &Vec3) -> Option<Ray> {
-        let out = incident.reflect(normal);
+    fn ray_bounce(&self, ray: &Ray, normal: &Vec3, position: &Vec3) -> Option<Bounce> {
+        let incident = ray.direction.normalize_or_zero();
+        let normal = normal.normalize_or_zero();
+        let normal = if incident.dot(normal) > 0.0 {
+            -normal
+        } else {
+            normal
+        };
+        let view = -incident;
 
-        Some(Ray {
-            origin: *position,
-            direction: out,
-            ior: 1.0,
+        if self.roughness <= 0.0 {
+            return Some(Bounce {
+                ray: Ray::new(*position, incident.reflect(&normal), ray.media),
+                attenuation: schlick_reflectance(self.color.into(), view.dot(normal)),
+                pdf: None,
+            });
+        }
+
+        //Importance sample the visible microfacets
+        let alpha = self.alpha();
+        let half = sample_ggx_visible_normal(view, normal, alpha);
+        let direction = incident.reflect(&half);
+        let cos_out = direction.dot(normal);
+        if cos_out <= 0.0 {
+            return None;
+        }
+
+        let (value, pdf) = ggx_reflection(view.dot(normal), cos_out, half.dot(normal), alpha);
+
+        Some(Bounce {
+            ray: Ray::new(*position, direction, ray.media),
+            attenuation: schlick_reflectance(self.color.into(), view.dot(half)) * (value / pdf),
+            pdf: Some(pdf),
+        })
+    }
+
+    //Cook-Torrance reflection with the pdf of visible normal sampling
+    fn evaluate_bounce(
+        &self,
+        incident: &Vec3,
+        normal: &Vec3,
+        direction: &Vec3,
+    ) -> Option<BounceEvaluation> {
+        //Mirrors can not be sampled towards lights
+        if self.roughness <= 0.0 {
+            return None;
+        }
+
+        let view = -incident.normalize_or_zero();
+        let normal = normal.normalize_or_zero();
+        let normal = if view.dot(normal) < 0.0 {
+            -normal
+        } else {
+            normal
+        };
+        let direction = direction.normalize_or_zero();
+
+        let cos_view = view.dot(normal);
+        let cos_out = direction.dot(normal);
+        if cos_view <= 0.0 || cos_out <= 0.0 {
+            return Some(BounceEvaluation {
+                value: Rgb::<f32>::BLACK,
+                pdf: 0.0,
+            });
+        }
+
+        let half = (view + direction).normalize();
+        let (value, pdf) = ggx_reflection(cos_view, cos_out, half.dot(normal), self.alpha());
+
+        Some(BounceEvaluation {
+            value: schlick_reflectance(self.color.into(), view.dot(half)) * value,
+            pdf,
         })
     }
 }
 
-//Metal
+//Transparent
 pub struct TransparentMaterial {
     pub color: Rgb<u8>,
     pub ior: f32,
+    //Where volumes overlap, the one with the highest priority is the one inside
+    pub priority: u32,
+    pub absorption: Vec3,
+    //Identifies the volume, shared by every primitive built from the same scene material
+    pub medium_id: usize,
+}
+
+impl TransparentMaterial {
+    fn medium(&self) -> Medium {
+        Medium {
+            id: self.medium_id,
+            ior: self.ior,
+            priority: self.priority,
+            absorption: self.absorption,
+        }
+    }
 }
 
 impl RaytraceMaterial for TransparentMaterial {}
@@ -126,21 +403,430 @@ impl Material for TransparentMaterial {
     fn get_ior(&self) -> Option<f32> {
         Some(self.ior)
     }
+
+    fn emitted(&self) -> Rgb<f32> {
+        Rgb::<f32>::BLACK
+    }
 }
 
 impl RayBounce for TransparentMaterial {
-    fn ray_bounce(&self, incident: &Vec3, normal: &Vec3, position: &Vec3) -> Option<Ray> {
-        let position = *position;
+    fn ray_bounce(&self, ray: &Ray, normal: &Vec3, position: &Vec3) -> Option<Bounce> {
+        Some(dielectric_bounce(
+            self.medium(),
+            self.color.into(),
+            ray,
+            normal,
+            position,
+        ))
+    }
+
+    fn evaluate_bounce(
+        &self,
+        _incident: &Vec3,
+        _normal: &Vec3,
+        _direction: &Vec3,
+    ) -> Option<BounceEvaluation> {
+        None
+    }
+}
+
+//Principled
+//Clearcoat layered over a blend of metal, glossy diffuse with sheen and smooth glass
+//Based on [https://media.disneyanimation.com/uploads/production/publication_asset/48/asset/s2012_pbs_disney_brdf_notes_v3.pdf]
+pub struct PrincipledMaterial {
+    pub color: Rgb<u8>,
+    pub metallic: f32,
+    pub roughness: f32,
+    //Reflectance of dielectrics, 0.5 is the usual 4%
+    pub specular: f32,
+    pub transmission: f32,
+    pub ior: f32,
+    pub clearcoat: f32,
+    pub sheen: f32,
+    //Identifies the volume, like `TransparentMaterial::medium_id`
+    pub medium_id: usize,
+}
+
+//Fixed glossy clearcoat, with the reflectance of an ior of 1.5
+const CLEARCOAT_ALPHA: f32 = 0.01;
+const CLEARCOAT_F0: f32 = 0.04;
 
-        let is_inside = incident.dot(*normal) > 0.0;
-        let ior_ratio = if is_inside { self.ior } else { 1.0 / self.ior };
+//Probabilities of sampling each lobe, glass is the remaining one
+struct PrincipledLobes {
+    clearcoat: f32,
+    specular: f32,
+    diffuse: f32,
+}
 
-        let refract_direction = incident.refract(normal, ior_ratio);
+impl PrincipledMaterial {
+    fn alpha(&self) -> f32 {
+        (self.roughness * self.roughness).max(1e-3)
+    }
 
-        Some(Ray {
-            origin: position,
-            direction: refract_direction,
+    fn medium(&self) -> Medium {
+        Medium {
+            id: self.medium_id,
             ior: self.ior,
+            priority: 0,
+            absorption: Vec3::ZERO,
+        }
+    }
+
+    fn clearcoat_reflectance(&self, cos: f32) -> f32 {
+        self.clearcoat * (CLEARCOAT_F0 + (1.0 - CLEARCOAT_F0) * schlick_weight(cos))
+    }
+
+    fn dielectric_reflectance(&self, cos: f32) -> f32 {
+        let f0 = 0.08 * self.specular;
+        f0 + (1.0 - f0) * schlick_weight(cos)
+    }
+
+    //Shares of the energy left under the clearcoat
+    fn opaque_weight(&self) -> f32 {
+        (1.0 - self.metallic) * (1.0 - self.transmission)
+    }
+
+    fn glass_weight(&self) -> f32 {
+        (1.0 - self.metallic) * self.transmission
+    }
+
+    //Metal and opaque dielectric reflections share the specular lobe
+    fn specular_reflectance(&self, cos: f32) -> Rgb<f32> {
+        schlick_reflectance(self.color.into(), cos) * self.metallic
+            + Rgb::<f32>::WHITE * (self.dielectric_reflectance(cos) * self.opaque_weight())
+    }
+
+    //Proportional to the energy each lobe reflects towards `view`
+    fn lobes(&self, cos_view: f32) -> PrincipledLobes {
+        let clearcoat = self.clearcoat_reflectance(cos_view);
+        let base = 1.0 - clearcoat;
+
+        let specular = self.specular_reflectance(cos_view);
+        let specular = base * (specular[0] + specular[1] + specular[2]) / 3.0;
+        let diffuse = base * self.opaque_weight() * (1.0 - self.dielectric_reflectance(cos_view));
+        let glass = base * self.glass_weight();
+
+        let total = clearcoat + specular + diffuse + glass;
+        if total <= 0.0 {
+            return PrincipledLobes {
+                clearcoat: 0.0,
+                specular: 1.0,
+                diffuse: 0.0,
+            };
+        }
+
+        PrincipledLobes {
+            clearcoat: clearcoat / total,
+            specular: specular / total,
+            diffuse: diffuse / total,
+        }
+    }
+
+    //Every lobe but glass, `normal` faces `view`
+    fn evaluate(&self, view: Vec3, normal: Vec3, direction: Vec3) -> BounceEvaluation {
+        let cos_view = view.dot(normal);
+        let cos_out = direction.dot(normal);
+        if cos_view <= 0.0 || cos_out <= 0.0 {
+            return BounceEvaluation {
+                value: Rgb::<f32>::BLACK,
+                pdf: 0.0,
+            };
+        }
+
+        let half = (view + direction).normalize();
+        let cos_half = half.dot(normal);
+        let cos_difference = view.dot(half);
+
+        let (clearcoat, clearcoat_pdf) =
+            ggx_reflection(cos_view, cos_out, cos_half, CLEARCOAT_ALPHA);
+        let (specular, specular_pdf) = ggx_reflection(cos_view, cos_out, cos_half, self.alpha());
+        let diffuse_pdf = cos_out / PI;
+
+        //Light not reflected by the specular lobe is scattered below it
+        let diffuse = Rgb::<f32>::from(self.color)
+            * ((1.0 - self.dielectric_reflectance(cos_view)) / PI)
+            + Rgb::<f32>::WHITE * (self.sheen * schlick_weight(cos_difference));
+
+        let base = self.specular_reflectance(cos_difference) * specular
+            + diffuse * (self.opaque_weight() * cos_out);
+        let value = Rgb::<f32>::WHITE * (self.clearcoat_reflectance(cos_difference) * clearcoat)
+            + base * (1.0 - self.clearcoat_reflectance(cos_view));
+
+        let lobes = self.lobes(cos_view);
+        let pdf = lobes.clearcoat * clearcoat_pdf
+            + lobes.specular * specular_pdf
+            + lobes.diffuse * diffuse_pdf;
+
+        BounceEvaluation { value, pdf }
+    }
+}
+
+impl RaytraceMaterial for PrincipledMaterial {}
+
+impl Material for PrincipledMaterial {
+    fn get_color(&self) -> Rgb<u8> {
+        self.color
+    }
+
+    fn get_ior(&self) -> Option<f32> {
+        (self.transmission > 0.0).then_some(self.ior)
+    }
+
+    fn emitted(&self) -> Rgb<f32> {
+        Rgb::<f32>::BLACK
+    }
+}
+
+impl RayBounce for PrincipledMaterial {
+    fn ray_bounce(&self, ray: &Ray, normal: &Vec3, position: &Vec3) -> Option<Bounce> {
+        //Rays that entered the glass only see its boundary
+        let medium = self.medium();
+        if ray.media.contains(medium.id) {
+            return Some(dielectric_bounce(
+                medium,
+                Rgb::<f32>::WHITE,
+                ray,
+                normal,
+                position,
+            ));
+        }
+
+        let view = -ray.direction.normalize_or_zero();
+        let front_normal = normal.normalize_or_zero();
+        let front_normal = if view.dot(front_normal) < 0.0 {
+            -front_normal
+        } else {
+            front_normal
+        };
+        let cos_view = view.dot(front_normal);
+        let lobes = self.lobes(cos_view);
+
+        let u = fastrand::f32();
+        let direction = if u < lobes.clearcoat {
+            let half = sample_ggx_visible_normal(view, front_normal, CLEARCOAT_ALPHA);
+            (-view).reflect(&half)
+        } else if u < lobes.clearcoat + lobes.specular {
+            let half = sample_ggx_visible_normal(view, front_normal, self.alpha());
+            (-view).reflect(&half)
+        } else if u < lobes.clearcoat + lobes.specular + lobes.diffuse {
+            sample_cosine_direction(front_normal)
+        } else {
+            //Glass is specular, its sample is only weighted by its share
+            let glass_probability = 1.0 - lobes.clearcoat - lobes.specular - lobes.diffuse;
+            let weight = (1.0 - self.clearcoat_reflectance(cos_view)) * self.glass_weight()
+                / glass_probability.max(f32::EPSILON);
+            let bounce = dielectric_bounce(medium, self.color.into(), ray, normal, position);
+
+            return Some(Bounce {
+                attenuation: bounce.attenuation * weight,
+                ..bounce
+            });
+        };
+
+        let evaluation = self.evaluate(view, front_normal, direction);
+        if evaluation.pdf <= 0.0 {
+            return None;
+        }
+
+        Some(Bounce {
+            ray: Ray::new(*position, direction, ray.media),
+            attenuation: evaluation.value * (1.0 / evaluation.pdf),
+            pdf: Some(evaluation.pdf),
         })
     }
+
+    fn evaluate_bounce(
+        &self,
+        incident: &Vec3,
+        normal: &Vec3,
+        direction: &Vec3,
+    ) -> Option<BounceEvaluation> {
+        let view = -incident.normalize_or_zero();
+        let normal = normal.normalize_or_zero();
+        let normal = if view.dot(normal) < 0.0 {
+            -normal
+        } else {
+            normal
+        };
+
+        Some(self.evaluate(view, normal, direction.normalize_or_zero()))
+    }
+}
+
+//Emissive
+pub struct EmissiveMaterial {
+    pub color: Rgb<u8>,
+    pub strength: f32,
+}
+
+impl RaytraceMaterial for EmissiveMaterial {}
+
+impl Material for EmissiveMaterial {
+    fn get_color(&self) -> Rgb<u8> {
+        self.color
+    }
+
+    fn get_ior(&self) -> Option<f32> {
+        None
+    }
+
+    fn emitted(&self) -> Rgb<f32> {
+        Rgb::<f32>::from(self.color) * self.strength
+    }
+}
+
+//Lights absorb every ray that hits them
+impl RayBounce for EmissiveMaterial {
+    fn ray_bounce(&self, _ray: &Ray, _normal: &Vec3, _position: &Vec3) -> Option<Bounce> {
+        None
+    }
+
+    fn evaluate_bounce(
+        &self,
+        _incident: &Vec3,
+        _normal: &Vec3,
+        _direction: &Vec3,
+    ) -> Option<BounceEvaluation> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::ray::MediumStack, *};
+
+    const SAMPLE_COUNT: usize = 100_000;
+
+    fn random_unit_vector() -> Vec3 {
+        loop {
+            let v = vec3(fastrand::f32(), fastrand::f32(), fastrand::f32()) * 2.0 - 1.0;
+            if (0.01..=1.0).contains(&v.length_squared()) {
+                return v.normalize();
+            }
+        }
+    }
+
+    //Histogram of the cosine to the normal against its density of `2 * cos`
+    #[test]
+    fn cosine_direction_distribution() {
+        const BIN_COUNT: usize = 16;
+        //99.9th percentile of the chi-square distribution with 15 degrees of freedom
+        const CHI_SQUARE_BOUND: f32 = 37.7;
+
+        fastrand::seed(17);
+        let mut bins = [0usize; BIN_COUNT];
+        for _ in 0..SAMPLE_COUNT {
+            let normal = random_unit_vector();
+            let direction = sample_cosine_direction(normal);
+            let cos = normal.dot(direction);
+
+            assert!((direction.length() - 1.0).abs() < 1e-4);
+            //Allows for rounding in the tangent frame
+            assert!(cos > -1e-6, "Direction below the hemisphere, cosine {cos}");
+            bins[((cos.max(0.0) * BIN_COUNT as f32) as usize).min(BIN_COUNT - 1)] += 1;
+        }
+
+        let chi_square: f32 = bins
+            .iter()
+            .enumerate()
+            .map(|(i, &count)| {
+                let (low, high) = (
+                    i as f32 / BIN_COUNT as f32,
+                    (i + 1) as f32 / BIN_COUNT as f32,
+                );
+                let expected = (high * high - low * low) * SAMPLE_COUNT as f32;
+                (count as f32 - expected).powi(2) / expected
+            })
+            .sum();
+        assert!(chi_square < CHI_SQUARE_BOUND, "Chi-square {chi_square}");
+    }
+
+    #[test]
+    fn diffuse_pdf_is_cosine_over_pi() {
+        fastrand::seed(17);
+        let material = DiffuseMaterial {
+            color: Rgb([255, 255, 255]),
+        };
+
+        for _ in 0..1000 {
+            let normal = random_unit_vector();
+            //Rays hitting either side of the surface
+            let incident = random_unit_vector();
+            let ray = Ray::new(Vec3::ZERO, incident, MediumStack::EMPTY);
+            let bounce = material.ray_bounce(&ray, &normal, &Vec3::ZERO).unwrap();
+            let direction = bounce.ray.direction;
+
+            let facing_normal = if incident.dot(normal) > 0.0 {
+                -normal
+            } else {
+                normal
+            };
+            let expected = facing_normal.dot(direction) / PI;
+            assert!((bounce.pdf.unwrap() - expected).abs() < 1e-6);
+
+            let evaluation = material
+                .evaluate_bounce(&incident, &normal, &direction)
+                .unwrap();
+            assert!((evaluation.pdf - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn fresnel_reflectance_limits() {
+        //Air to glass at normal incidence, ((1.5 - 1) / (1.5 + 1))^2
+        assert!((fresnel_reflectance(1.0, 1.0 / 1.5) - 0.04).abs() < 1e-4);
+
+        //Glass to air past the critical angle, whose cosine is about 0.745
+        for cos_incident in [0.0, 0.3, 0.5, 0.7] {
+            assert_eq!(fresnel_reflectance(cos_incident, 1.5), 1.0);
+        }
+        assert!(fresnel_reflectance(0.8, 1.5) < 1.0);
+    }
+
+    //How often glass reflects light hitting it at several angles, from both sides
+    #[test]
+    fn dielectric_bounce_follows_fresnel() {
+        fastrand::seed(12);
+        let material = TransparentMaterial {
+            color: Rgb([255, 255, 255]),
+            ior: 1.5,
+            priority: 0,
+            absorption: Vec3::ZERO,
+            medium_id: 1,
+        };
+        let normal = Vec3::Y;
+
+        for angle in [0.0_f32, 30.0, 45.0, 60.0, 80.0] {
+            let (sin, cos) = angle.to_radians().sin_cos();
+            let outside_ray = Ray::new(Vec3::ZERO, vec3(sin, -cos, 0.0), MediumStack::EMPTY);
+            let inside_ray = Ray::new(
+                Vec3::ZERO,
+                vec3(sin, cos, 0.0),
+                MediumStack::EMPTY.with(material.medium()),
+            );
+
+            for (ray, ior_ratio) in [(outside_ray, 1.0 / 1.5), (inside_ray, 1.5)] {
+                let mut reflection_count = 0;
+                for _ in 0..SAMPLE_COUNT {
+                    let bounce = material.ray_bounce(&ray, &normal, &Vec3::ZERO).unwrap();
+                    let direction = bounce.ray.direction;
+                    assert!(direction.is_finite() && (direction.length() - 1.0).abs() < 1e-4);
+
+                    //Reflections stay on the incident side
+                    if direction.dot(normal).signum() != ray.direction.dot(normal).signum() {
+                        reflection_count += 1;
+                    }
+                }
+
+                let expected = fresnel_reflectance(cos, ior_ratio);
+                let observed = reflection_count as f32 / SAMPLE_COUNT as f32;
+                //Four standard deviations of the binomial distribution
+                let tolerance = 4.0 * (expected * (1.0 - expected) / SAMPLE_COUNT as f32).sqrt();
+                assert!(
+                    (observed - expected).abs() <= tolerance.max(1e-6),
+                    "At {angle} degrees with an ior ratio of {ior_ratio}, {observed} reflected instead of {expected}"
+                );
+            }
+        }
+    }
 }
@@ -1,9 +1,15 @@
 #![allow(dead_code)]
 
+pub mod bvh;
 pub mod camera;
+pub mod gltf;
+pub mod gpu;
 pub mod material;
+pub mod obj;
 pub mod ray;
+pub mod scene;
 pub mod shape;
+pub mod world;
 
 pub use crate::{
     colors::{Rgb, Rgba},
@@ -14,11 +20,13 @@ use clap::ValueEnum;
 #[allow(unused_imports)]
 use log::{debug, info, log, trace, warn};
 
+use bvh::Aabb;
 use camera::{Camera, Resolution, Viewport};
-use material::{DiffuseMaterial, Material, MetalMaterial, TransparentMaterial};
+use material::Material;
 use ray::Ray;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
-use shape::Sphere;
+use scene::Scene;
+use world::World;
 
 use std::{
     sync::{Arc, Mutex},
@@ -26,22 +34,53 @@ use std::{
 };
 
 use anyhow::Result;
-use glam::{vec3, Vec3};
+use glam::{Vec2, Vec3};
 use indicatif::{ParallelProgressIterator, ProgressBar, ProgressFinish, ProgressStyle};
 
 pub struct RaycastHit<'a> {
     pub distance: f32,
     pub point: Vec3,
     pub normal: Vec3,
+    pub uv: Vec2,
     pub object: &'a dyn Raytrace,
 }
 
+//Scattering of a bounce direction, `value` includes the cosine term and `pdf` is per solid angle
+pub struct BounceEvaluation {
+    pub value: Rgb<f32>,
+    pub pdf: f32,
+}
+
+//Ray leaving a surface, `attenuation` multiplies the light it brings back
+//`pdf` is the one `ray` was sampled with, None for specular bounces
+pub struct Bounce {
+    pub ray: Ray,
+    pub attenuation: Rgb<f32>,
+    pub pdf: Option<f32>,
+}
+
 pub trait RayBounce {
-    fn ray_bounce(&self, incident: &Vec3, normal: &Vec3, position: &Vec3) -> Option<Ray>;
+    fn ray_bounce(&self, ray: &Ray, normal: &Vec3, position: &Vec3) -> Option<Bounce>;
+    //None for specular bounces, which only scatter in the sampled direction
+    fn evaluate_bounce(
+        &self,
+        incident: &Vec3,
+        normal: &Vec3,
+        direction: &Vec3,
+    ) -> Option<BounceEvaluation>;
+}
+
+//Point sampled uniformly over the surface of an object
+pub struct SurfaceSample {
+    pub point: Vec3,
+    pub normal: Vec3,
 }
 
 pub trait RayCast {
-    fn ray_cast(&self, ray: &Ray) -> Option<RaycastHit>;
+    fn ray_cast(&self, ray: &Ray) -> Option<RaycastHit<'_>>;
+    fn bounding_box(&self) -> Aabb;
+    fn surface_area(&self) -> f32;
+    fn sample_surface(&self) -> SurfaceSample;
 }
 
 pub trait Raytrace: RayBounce + RayCast + Material {}
@@ -53,62 +92,45 @@ pub enum RenderMode {
     Gpu,
 }
 
-pub fn render(texture: &mut Texture, sample_count: u32, mode: RenderMode) -> Result<()> {
-    let camera = Camera::new(vec3(0.0, 0.0, 1.0), 2.0, vec3(0.0, 0.0, 0.0), 1.0);
+pub fn render(
+    texture: &mut Texture,
+    scene: &Scene,
+    sample_count: u32,
+    max_bounces: u32,
+    mode: RenderMode,
+    workgroup_size: Option<u32>,
+) -> Result<()> {
+    let camera = &scene.camera;
 
     let resolution = Resolution {
         width: texture.width as u32,
         height: texture.height as u32,
     };
 
-    let world: Vec<Box<dyn Raytrace + Sync>> = vec![
-        //Diffuse
-        Box::new(Sphere {
-            position: vec3(0.0, 0.0, -1.4),
-            radius: 0.5,
-            material: Box::new(DiffuseMaterial {
-                color: Rgb([25, 52, 125]),
-            }),
-        }),
-        //Transparent
-        Box::new(Sphere {
-            position: vec3(-1.0, 0.0, -1.0),
-            radius: 0.5,
-            material: Box::new(TransparentMaterial {
-                color: Rgb([200, 200, 200]),
-                ior: 1.5,
-            }),
-        }),
-        //Metal
-        Box::new(Sphere {
-            position: vec3(1.0, 0.0, -1.0),
-            radius: 0.5,
-            material: Box::new(MetalMaterial {
-                color: Rgb([200, 150, 50]),
-            }),
-        }),
-        //Ground
-        Box::new(Sphere {
-            position: vec3(0.0, -20000.5, -1.0),
-            radius: 20000.0,
-            material: Box::new(DiffuseMaterial {
-                color: Rgb([205, 205, 0]),
-            }),
-        }),
-    ];
-
     match mode {
-        RenderMode::SingleThread => {
-            render_single_thread(texture, &camera, resolution, &world, sample_count)
-        }
+        RenderMode::SingleThread => render_single_thread(
+            texture,
+            camera,
+            resolution,
+            &scene.world(),
+            sample_count,
+            max_bounces,
+        ),
         RenderMode::MultiThread => render_multi_thread(
             Arc::new(Mutex::new(texture)),
-            &camera,
+            camera,
             resolution,
-            &world,
+            &scene.world(),
             sample_count,
+            max_bounces,
         ),
-        RenderMode::Gpu => todo!(),
+        RenderMode::Gpu => pollster::block_on(gpu::render_offscreen(
+            texture,
+            scene,
+            sample_count,
+            max_bounces,
+            workgroup_size,
+        )),
     }
 }
 
@@ -116,8 +138,9 @@ fn render_single_thread(
     texture: &mut Texture,
     camera: &Camera,
     resolution: Resolution,
-    world: &Vec<Box<dyn Raytrace + Sync>>,
+    world: &World,
     sample_count: u32,
+    max_bounces: u32,
 ) -> Result<()> {
     let progress_bar = ProgressBar::new(texture.width as u64).with_style(
         ProgressStyle::with_template(
@@ -126,7 +149,7 @@ fn render_single_thread(
         .expect("Setting template"),
     );
 
-    let viewport = Viewport::new(camera.size, resolution, camera);
+    let viewport = Viewport::new(resolution, camera);
 
     info!("Starting Single-thread CPU Rendering...");
     let start_time = SystemTime::now();
@@ -144,12 +167,8 @@ fn render_single_thread(
                     + (x + random_x_offset) * viewport.delta_u
                     + (y + random_y_offset) * viewport.delta_v;
 
-                let ray = Ray::new(
-                    camera.position,
-                    viewport_pixel_position - camera.position,
-                    1.0,
-                );
-                let pixel_color = render_pixel_sample(&ray, world);
+                let ray = camera.primary_ray(viewport_pixel_position);
+                let pixel_color = render_pixel_sample(&ray, world, max_bounces);
 
                 sum_color = Rgb([
                     sum_color[0] + pixel_color[0],
@@ -184,8 +203,9 @@ fn render_multi_thread(
     texture: Arc<Mutex<&mut Texture>>,
     camera: &Camera,
     resolution: Resolution,
-    world: &Vec<Box<dyn Raytrace + Sync>>,
+    world: &World,
     sample_count: u32,
+    max_bounces: u32,
 ) -> Result<()> {
     let texture_width = texture.lock().unwrap().width;
     let texture_height = texture.lock().unwrap().height;
@@ -197,7 +217,7 @@ fn render_multi_thread(
         .expect("Setting template"),
     ).with_finish(ProgressFinish::AndLeave);
 
-    let viewport = Viewport::new(camera.size, resolution, camera);
+    let viewport = Viewport::new(resolution, camera);
 
     info!("Starting Multi-Thread CPU Rendering...");
     let start_time = SystemTime::now();
@@ -218,12 +238,8 @@ fn render_multi_thread(
                         + (x + random_x_offset) * viewport.delta_u
                         + (y + random_y_offset) * viewport.delta_v;
 
-                    let ray = Ray::new(
-                        camera.position,
-                        viewport_pixel_position - camera.position,
-                        1.0,
-                    );
-                    let pixel_color = render_pixel_sample(&ray, world);
+                    let ray = camera.primary_ray(viewport_pixel_position);
+                    let pixel_color = render_pixel_sample(&ray, world, max_bounces);
 
                     sum_color = Rgb([
                         sum_color[0] + pixel_color[0],
@@ -258,51 +274,126 @@ fn render_multi_thread(
     Ok(())
 }
 
-fn render_pixel_sample(ray: &Ray, objects: &Vec<Box<dyn Raytrace + Sync>>) -> Rgb<f32> {
-    let max_ray_bounce = 1024;
+//Paths longer than this many bounces are cut randomly, depending on the light they still carry
+const RUSSIAN_ROULETTE_DEPTH: u32 = 3;
+
+//Iterative path tracer, lights are both hit by bounces and sampled directly
+fn render_pixel_sample(ray: &Ray, world: &World, max_bounces: u32) -> Rgb<f32> {
+    let mut radiance = Rgb::<f32>::BLACK;
+    let mut throughput = Rgb::<f32>::WHITE;
+    let mut ray = *ray;
+    //pdf of the bounce that produced `ray`, None for camera rays and specular bounces
+    let mut bounce_pdf: Option<f32> = None;
+
+    for bounce_count in 0..=max_bounces {
+        let Some(closest_hit) = world.ray_cast(&ray) else {
+            let transmittance = ray.media.transmittance(f32::INFINITY);
+            radiance = radiance + throughput * transmittance * background_color(&ray, 1.0);
+            break;
+        };
+        let object = closest_hit.object;
+        throughput *= ray
+            .media
+            .transmittance((closest_hit.point - ray.origin).length());
+
+        //Lights hit by a sampled bounce are weighted against light sampling
+        let emitted = match bounce_pdf {
+            Some(bounce_pdf) => {
+                let light_pdf = world.light_pdf(object, &ray, &closest_hit);
+                object.emitted() * power_heuristic(bounce_pdf, light_pdf)
+            }
+            None => object.emitted(),
+        };
+        let direct_light =
+            sample_direct_light(&ray, &closest_hit, world, bounce_count == max_bounces);
+        radiance = radiance + throughput * (emitted + direct_light);
+
+        if bounce_count == max_bounces {
+            break;
+        }
+
+        //Absorbed rays only carry the emitted light
+        let Some(bounce) = object.ray_bounce(&ray, &closest_hit.normal, &closest_hit.point) else {
+            break;
+        };
+        throughput *= bounce.attenuation;
+
+        //Surviving paths are brightened by the odds of being cut, keeping the estimate unbiased
+        if bounce_count >= RUSSIAN_ROULETTE_DEPTH {
+            let survival = throughput[0]
+                .max(throughput[1])
+                .max(throughput[2])
+                .min(0.95);
+            if fastrand::f32() >= survival {
+                break;
+            }
+            throughput = throughput * (1.0 / survival);
+        }
 
-    get_ray_color(ray, objects, 0, max_ray_bounce)
+        ray = bounce.ray;
+        bounce_pdf = bounce.pdf;
+    }
+
+    radiance
 }
 
-fn get_ray_color(
+//Next event estimation, traces a shadow ray to a point sampled on a random light
+//On the last bounce no light can be hit by a further bounce, so light sampling takes the full weight
+fn sample_direct_light(
     ray: &Ray,
-    objects: &Vec<Box<dyn Raytrace + Sync>>,
-    iteration_count: u32,
-    max_iteration: u32,
+    hit: &RaycastHit,
+    world: &World,
+    is_last_bounce: bool,
 ) -> Rgb<f32> {
-    if iteration_count > max_iteration {
+    let Some((light, light_selection_pdf)) = world.pick_light() else {
         return Rgb::<f32>::BLACK;
     };
 
-    let mut closest_hit: Option<RaycastHit> = Option::None;
-    let mut min_distance = f32::INFINITY;
-    for object in objects {
-        if let Some(hit) = object.ray_cast(ray) {
-            if min_distance > hit.distance {
-                min_distance = hit.distance;
-                closest_hit = Some(hit);
-            }
-        }
+    let sample = light.sample_surface();
+    let to_light = sample.point - hit.point;
+    let distance_squared = to_light.length_squared();
+    let direction = to_light / distance_squared.sqrt();
+
+    let Some(evaluation) = hit
+        .object
+        .evaluate_bounce(&ray.direction, &hit.normal, &direction)
+    else {
+        return Rgb::<f32>::BLACK;
+    };
+    let light_cosine = sample.normal.dot(direction).abs();
+    if evaluation.pdf <= 0.0 || light_cosine <= 0.0 {
+        return Rgb::<f32>::BLACK;
+    }
+
+    //The light is reached at a distance of 1 along `to_light`
+    let shadow_ray = Ray::new(hit.point, to_light, ray.media);
+    if world
+        .ray_cast(&shadow_ray)
+        .is_some_and(|occluder| occluder.distance < 1.0 - 1e-3)
+    {
+        return Rgb::<f32>::BLACK;
     }
 
-    if let Some(closest_hit) = closest_hit {
-        let object_color = Rgb::<f32>::from(closest_hit.object.get_color());
-        let bounce_ray =
-            closest_hit
-                .object
-                .ray_bounce(&ray.direction, &closest_hit.normal, &closest_hit.point);
+    let light_pdf = light_selection_pdf * distance_squared / (light_cosine * light.surface_area());
+    let weight = match is_last_bounce {
+        true => 1.0,
+        false => power_heuristic(light_pdf, evaluation.pdf),
+    };
 
-        return if let Some(bounce_ray) = bounce_ray {
-            let out_ray_color =
-                get_ray_color(&bounce_ray, objects, iteration_count + 1, max_iteration);
+    //Shadow rays never leave the medium the hit point is in, as transparent objects occlude them
+    let transmittance = ray.media.transmittance(distance_squared.sqrt());
 
-            object_color * out_ray_color
-        } else {
-            background_color(ray, 1.0)
-        };
-    }
+    light.emitted() * transmittance * evaluation.value * (weight / light_pdf)
+}
 
-    background_color(ray, 1.0)
+//Multiple importance sampling weight of the strategy with `pdf`
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (pdf, other_pdf) = (pdf * pdf, other_pdf * other_pdf);
+    if pdf + other_pdf > 0.0 {
+        pdf / (pdf + other_pdf)
+    } else {
+        0.0
+    }
 }
 
 fn background_color(ray: &Ray, blend_factor: f32) -> Rgb<f32> {
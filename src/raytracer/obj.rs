@@ -0,0 +1,566 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs,
+    path::Path,
+    str::{FromStr, SplitWhitespace},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use glam::{Vec2, Vec3};
+
+use super::{scene::MaterialDescription, shape::Mesh, Rgb};
+
+//Faces sharing a material, `material` is None for faces before any `usemtl`
+pub struct ObjGroup {
+    pub material: Option<String>,
+    pub mesh: Mesh,
+}
+
+pub struct ObjModel {
+    pub groups: Vec<ObjGroup>,
+    pub materials: HashMap<String, MaterialDescription>,
+}
+
+impl ObjModel {
+    pub fn load(path: &Path, load_materials: bool) -> Result<Self> {
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read OBJ file {}", path.display()))?;
+
+        let directory = path.parent().unwrap_or(Path::new(""));
+        Self::parse(&source, directory, load_materials)
+            .with_context(|| format!("Failed to load OBJ file {}", path.display()))
+    }
+
+    //Material libraries are looked up relative to `directory`,
+    //without `load_materials` they are skipped and `usemtl` only groups the faces
+    pub fn parse(source: &str, directory: &Path, load_materials: bool) -> Result<Self> {
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut uvs = Vec::new();
+
+        let mut materials = HashMap::new();
+        let mut groups: Vec<GroupBuilder> = Vec::new();
+        let mut current_group = None;
+        //Line of the first `usemtl` of each material name
+        let mut first_uses = HashMap::new();
+
+        for (line_index, line) in source.lines().enumerate() {
+            let line_number = line_index + 1;
+            let line_error = |message: String| anyhow!("line {line_number}: {message}");
+
+            let line = line.split('#').next().unwrap_or_default();
+            let mut tokens = line.split_whitespace();
+            let Some(keyword) = tokens.next() else {
+                continue;
+            };
+
+            match keyword {
+                "v" => positions.push(
+                    parse_vec3(&mut tokens, 3)
+                        .map_err(|e| line_error(format!("invalid vertex position: {e}")))?,
+                ),
+                "vn" => normals.push(
+                    parse_vec3(&mut tokens, 3)
+                        .map_err(|e| line_error(format!("invalid vertex normal: {e}")))?,
+                ),
+                "vt" => {
+                    let uv = parse_vec3(&mut tokens, 1)
+                        .map_err(|e| line_error(format!("invalid texture coordinate: {e}")))?;
+                    uvs.push(Vec2::new(uv.x, uv.y));
+                }
+                "f" => {
+                    let corners = tokens
+                        .map(|corner| {
+                            parse_corner(corner, positions.len(), uvs.len(), normals.len()).map_err(
+                                |e| line_error(format!("invalid face vertex `{corner}`: {e}")),
+                            )
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+
+                    if corners.len() < 3 {
+                        return Err(line_error(format!(
+                            "a face needs at least 3 vertices, got {}",
+                            corners.len()
+                        )));
+                    }
+
+                    let group = match current_group {
+                        Some(group) => group,
+                        None => {
+                            groups.push(GroupBuilder::new(None));
+                            current_group = Some(groups.len() - 1);
+                            groups.len() - 1
+                        }
+                    };
+                    groups[group].add_polygon(&corners);
+                }
+                "usemtl" => {
+                    let name = rest_of_line(line, keyword)
+                        .ok_or_else(|| line_error("missing material name".to_string()))?;
+                    //Checked once the whole file is read, as exporters may write `mtllib` after `usemtl`
+                    first_uses.entry(name.to_string()).or_insert(line_number);
+
+                    let name = Some(name.to_string());
+                    current_group = match groups.iter().position(|group| group.material == name) {
+                        Some(group) => Some(group),
+                        None => {
+                            groups.push(GroupBuilder::new(name));
+                            Some(groups.len() - 1)
+                        }
+                    };
+                }
+                "mtllib" if load_materials => {
+                    //A single library per line, so that paths may contain spaces
+                    let library = rest_of_line(line, keyword)
+                        .ok_or_else(|| line_error("missing material library path".to_string()))?;
+                    let library_materials = parse_mtl_file(&directory.join(library))
+                        .map_err(|e| line_error(format!("{e:#}")))?;
+                    materials.extend(library_materials);
+                }
+                //Grouping, smoothing, lines, points and free-form geometry are not rendered
+                _ => (),
+            }
+        }
+
+        if load_materials {
+            let unknown = first_uses
+                .into_iter()
+                .filter(|(name, _)| !materials.contains_key(name))
+                .min_by_key(|&(_, line_number)| line_number);
+            if let Some((name, line_number)) = unknown {
+                bail!(
+                    "line {line_number}: unknown material \"{name}\", it is not defined by any loaded material library"
+                );
+            }
+        }
+
+        let groups = groups
+            .into_iter()
+            .filter(|group| !group.indices.is_empty())
+            .map(|group| group.build(&positions, &uvs, &normals))
+            .collect();
+
+        Ok(ObjModel { groups, materials })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Corner {
+    position: usize,
+    uv: Option<usize>,
+    normal: Option<usize>,
+}
+
+//Deduplicates the corners of a group into mesh vertices
+struct GroupBuilder {
+    material: Option<String>,
+    corners: Vec<Corner>,
+    corner_indices: HashMap<Corner, u32>,
+    indices: Vec<[u32; 3]>,
+}
+
+impl GroupBuilder {
+    fn new(material: Option<String>) -> Self {
+        Self {
+            material,
+            corners: Vec::new(),
+            corner_indices: HashMap::new(),
+            indices: Vec::new(),
+        }
+    }
+
+    //Fan triangulation, polygons are expected to be convex
+    fn add_polygon(&mut self, corners: &[Corner]) {
+        let indices: Vec<u32> = corners
+            .iter()
+            .map(|&corner| match self.corner_indices.entry(corner) {
+                Entry::Occupied(entry) => *entry.get(),
+                Entry::Vacant(entry) => {
+                    self.corners.push(corner);
+                    *entry.insert(self.corners.len() as u32 - 1)
+                }
+            })
+            .collect();
+
+        for i in 1..indices.len() - 1 {
+            self.indices.push([indices[0], indices[i], indices[i + 1]]);
+        }
+    }
+
+    //Normals and uvs are only kept if every vertex of the group has them
+    fn build(self, positions: &[Vec3], uvs: &[Vec2], normals: &[Vec3]) -> ObjGroup {
+        let mesh = Mesh {
+            positions: self
+                .corners
+                .iter()
+                .map(|corner| positions[corner.position])
+                .collect(),
+            normals: self
+                .corners
+                .iter()
+                .map(|corner| Some(normals[corner.normal?]))
+                .collect(),
+            uvs: self
+                .corners
+                .iter()
+                .map(|corner| Some(uvs[corner.uv?]))
+                .collect(),
+            indices: self.indices,
+        };
+
+        ObjGroup {
+            material: self.material,
+            mesh,
+        }
+    }
+}
+
+//Parses `v`, `v/vt`, `v//vn` or `v/vt/vn`, negative indices are relative to the end
+fn parse_corner(
+    corner: &str,
+    position_count: usize,
+    uv_count: usize,
+    normal_count: usize,
+) -> Result<Corner> {
+    let mut indices = corner.split('/');
+
+    let position = resolve_index(indices.next(), position_count, "position")?
+        .ok_or_else(|| anyhow!("missing position index"))?;
+    let uv = resolve_index(indices.next(), uv_count, "texture coordinate")?;
+    let normal = resolve_index(indices.next(), normal_count, "normal")?;
+
+    if indices.next().is_some() {
+        bail!("too many indices");
+    }
+
+    Ok(Corner {
+        position,
+        uv,
+        normal,
+    })
+}
+
+fn resolve_index(index: Option<&str>, count: usize, name: &str) -> Result<Option<usize>> {
+    let Some(index) = index.filter(|index| !index.is_empty()) else {
+        return Ok(None);
+    };
+
+    let index: i64 = index
+        .parse()
+        .map_err(|_| anyhow!("`{index}` is not a valid {name} index"))?;
+
+    let resolved = match index {
+        1.. => index - 1,
+        ..=-1 => count as i64 + index,
+        0 => bail!("{name} indices start at 1"),
+    };
+    if resolved < 0 || resolved >= count as i64 {
+        bail!("{name} index {index} is out of range, {count} defined so far");
+    }
+
+    Ok(Some(resolved as usize))
+}
+
+//Parses at least `required` and at most 3 numbers, missing components are 0
+fn parse_vec3(tokens: &mut SplitWhitespace, required: usize) -> Result<Vec3> {
+    let mut values = [0.0; 3];
+    let mut count = 0;
+
+    for token in tokens {
+        //A trailing weight is allowed and ignored
+        if count == 3 {
+            continue;
+        }
+        values[count] = parse_number(token)?;
+        count += 1;
+    }
+
+    if count < required {
+        bail!("expected at least {required} numbers, got {count}");
+    }
+
+    Ok(Vec3::from_array(values))
+}
+
+//Either 3 components or a single grey level
+fn parse_color(tokens: &mut SplitWhitespace) -> Result<Vec3> {
+    let values = tokens.map(parse_number).collect::<Result<Vec<f32>>>()?;
+
+    match values[..] {
+        [grey] => Ok(Vec3::splat(grey)),
+        [r, g, b] => Ok(Vec3::new(r, g, b)),
+        _ => bail!("expected 1 or 3 numbers, got {}", values.len()),
+    }
+}
+
+fn parse_number<T: FromStr>(token: &str) -> Result<T> {
+    token
+        .parse()
+        .map_err(|_| anyhow!("`{token}` is not a number"))
+}
+
+//Names may contain spaces
+fn rest_of_line<'a>(line: &'a str, keyword: &str) -> Option<&'a str> {
+    let rest = line.trim_start()[keyword.len()..].trim();
+    (!rest.is_empty()).then_some(rest)
+}
+
+//Material library
+#[derive(Default)]
+struct MtlMaterial {
+    diffuse: Option<Vec3>,
+    specular: Option<Vec3>,
+    transmission: Option<Vec3>,
+    emission: Option<Vec3>,
+    ior: Option<f32>,
+    dissolve: Option<f32>,
+    metallic: Option<f32>,
+    roughness: Option<f32>,
+    shininess: Option<f32>,
+    illumination: Option<u32>,
+}
+
+impl MtlMaterial {
+    //Maps the emission and illumination model onto the closest raytracer material
+    fn describe(&self) -> MaterialDescription {
+        let to_color = |color: Vec3| {
+            let color = color.clamp(Vec3::ZERO, Vec3::ONE) * 255.0;
+            Rgb([color.x as u8, color.y as u8, color.z as u8])
+        };
+        let diffuse = self.diffuse.unwrap_or(Vec3::splat(0.8));
+
+        let transparent = matches!(self.illumination, Some(4 | 6 | 7 | 9))
+            || self.dissolve.is_some_and(|dissolve| dissolve < 1.0);
+        let metal = matches!(self.illumination, Some(3 | 5 | 8))
+            || self.metallic.is_some_and(|metallic| metallic >= 0.5);
+
+        if let Some(emission) = self
+            .emission
+            .filter(|emission| emission.max_element() > 0.0)
+        {
+            MaterialDescription::emissive(emission)
+        } else if transparent {
+            MaterialDescription::Transparent {
+                color: to_color(self.transmission.unwrap_or(Vec3::ONE)),
+                //Most exporters omit `Ni` for plain glass
+                ior: self.ior.filter(|&ior| ior > 0.0).unwrap_or(1.5),
+                priority: 0,
+                absorption: Vec3::ZERO,
+            }
+        } else if metal {
+            //PBR exports keep the metal tint in `Kd`
+            let color = match (self.metallic, self.specular) {
+                (Some(_), _) | (None, None) => diffuse,
+                (None, Some(specular)) => specular,
+            };
+            //Without PBR roughness, the Phong exponent is converted to the closest GGX lobe
+            let roughness = match (self.roughness, self.shininess) {
+                (Some(roughness), _) => roughness,
+                (None, Some(shininess)) => (2.0 / (shininess.max(0.0) + 2.0)).sqrt().sqrt(),
+                (None, None) => 0.0,
+            };
+            MaterialDescription::Metal {
+                color: to_color(color),
+                roughness: roughness.clamp(0.0, 1.0),
+            }
+        } else {
+            MaterialDescription::Diffuse {
+                color: to_color(diffuse),
+            }
+        }
+    }
+}
+
+fn parse_mtl_file(path: &Path) -> Result<HashMap<String, MaterialDescription>> {
+    let source = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read material library {}", path.display()))?;
+
+    parse_mtl(&source)
+        .with_context(|| format!("Failed to load material library {}", path.display()))
+}
+
+pub fn parse_mtl(source: &str) -> Result<HashMap<String, MaterialDescription>> {
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlMaterial)> = None;
+
+    for (line_index, line) in source.lines().enumerate() {
+        let line_number = line_index + 1;
+        let line_error = |message: String| anyhow!("line {line_number}: {message}");
+
+        let line = line.split('#').next().unwrap_or_default();
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+
+        if keyword == "newmtl" {
+            let name = rest_of_line(line, keyword)
+                .ok_or_else(|| line_error("missing material name".to_string()))?;
+
+            if let Some((name, material)) = current.take() {
+                materials.insert(name, material.describe());
+            }
+            current = Some((name.to_string(), MtlMaterial::default()));
+            continue;
+        }
+
+        let Some((_, material)) = current.as_mut() else {
+            return Err(line_error(format!("`{keyword}` before any `newmtl`")));
+        };
+
+        let parse_color = |tokens: &mut SplitWhitespace| {
+            parse_color(tokens).map_err(|e| line_error(format!("invalid `{keyword}` color: {e}")))
+        };
+        let parse_scalar = |tokens: &mut SplitWhitespace| -> Result<f32> {
+            let value = tokens
+                .next()
+                .ok_or_else(|| line_error(format!("missing `{keyword}` value")))?;
+            parse_number(value).map_err(|e| line_error(format!("invalid `{keyword}` value: {e}")))
+        };
+
+        match keyword {
+            "Kd" => material.diffuse = Some(parse_color(&mut tokens)?),
+            "Ks" => material.specular = Some(parse_color(&mut tokens)?),
+            "Ke" => material.emission = Some(parse_color(&mut tokens)?),
+            "Tf" => material.transmission = Some(parse_color(&mut tokens)?),
+            "Ni" => material.ior = Some(parse_scalar(&mut tokens)?),
+            "d" => material.dissolve = Some(parse_scalar(&mut tokens)?),
+            "Tr" => material.dissolve = Some(1.0 - parse_scalar(&mut tokens)?),
+            "Pm" => material.metallic = Some(parse_scalar(&mut tokens)?),
+            "Pr" => material.roughness = Some(parse_scalar(&mut tokens)?),
+            "Ns" => material.shininess = Some(parse_scalar(&mut tokens)?),
+            "illum" => {
+                let illumination = tokens
+                    .next()
+                    .ok_or_else(|| line_error("missing `illum` value".to_string()))?;
+                material.illumination = Some(illumination.parse().map_err(|_| {
+                    line_error(format!(
+                        "invalid `illum` value: `{illumination}` is not an illumination model"
+                    ))
+                })?);
+            }
+            //Textures and other properties are not supported
+            _ => (),
+        }
+    }
+
+    if let Some((name, material)) = current {
+        materials.insert(name, material.describe());
+    }
+
+    Ok(materials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(source: &str) -> String {
+        match ObjModel::parse(source, Path::new("missing directory"), true) {
+            Ok(_) => panic!("parsing should fail"),
+            Err(e) => format!("{e:#}"),
+        }
+    }
+
+    #[test]
+    fn malformed_vertices() {
+        let error = parse_error("v 0 0 0\n\nv 1 x 0\n");
+        assert!(
+            error.starts_with("line 3: invalid vertex position"),
+            "{error}"
+        );
+        assert!(error.contains("`x` is not a number"), "{error}");
+
+        let error = parse_error("v 0 0\n");
+        assert!(
+            error.starts_with("line 1: invalid vertex position"),
+            "{error}"
+        );
+        assert!(
+            error.contains("expected at least 3 numbers, got 2"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn malformed_faces() {
+        let vertices = "v 0 0 0\nv 1 0 0\nv 0 1 0\n";
+
+        let error = parse_error(&format!("{vertices}f 1 2\n"));
+        assert!(
+            error.starts_with("line 4: a face needs at least 3 vertices, got 2"),
+            "{error}"
+        );
+
+        let error = parse_error(&format!("{vertices}f 1 2 4\n"));
+        assert!(
+            error.starts_with("line 4: invalid face vertex `4`"),
+            "{error}"
+        );
+        assert!(
+            error.contains("position index 4 is out of range, 3 defined so far"),
+            "{error}"
+        );
+
+        let error = parse_error(&format!("{vertices}f 1 2 0\n"));
+        assert!(error.contains("position indices start at 1"), "{error}");
+
+        let error = parse_error(&format!("{vertices}f 1 2 3/1\n"));
+        assert!(
+            error.contains("texture coordinate index 1 is out of range"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn malformed_material_references() {
+        let error = parse_error("v 0 0 0\nusemtl\n");
+        assert!(
+            error.starts_with("line 2: missing material name"),
+            "{error}"
+        );
+
+        let error = parse_error("usemtl red\n\nusemtl dark blue\n");
+        assert!(
+            error.starts_with("line 1: unknown material \"red\""),
+            "{error}"
+        );
+
+        let error = parse_error("mtllib\n");
+        assert!(
+            error.starts_with("line 1: missing material library path"),
+            "{error}"
+        );
+
+        let error = parse_error("\nmtllib my materials.mtl\n");
+        assert!(
+            error.starts_with("line 2: Failed to read material library"),
+            "{error}"
+        );
+        assert!(error.contains("my materials.mtl"), "{error}");
+    }
+
+    #[test]
+    fn materials_may_be_used_before_their_library() {
+        let directory = std::env::temp_dir().join(format!("obj-test-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(
+            directory.join("scene materials.mtl"),
+            "newmtl red\nKd 1 0 0\n",
+        )
+        .unwrap();
+
+        let source = "v 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl red\nf 1 2 3\nmtllib scene materials.mtl\n";
+        let model = ObjModel::parse(source, &directory, true);
+        fs::remove_dir_all(&directory).unwrap();
+
+        let model = model.unwrap();
+        assert_eq!(model.groups.len(), 1);
+        assert_eq!(model.groups[0].material.as_deref(), Some("red"));
+        assert!(model.materials.contains_key("red"));
+
+        //Without materials, `usemtl` only groups the faces
+        let model = ObjModel::parse("usemtl red\n", Path::new(""), false).unwrap();
+        assert!(model.groups.is_empty());
+    }
+}
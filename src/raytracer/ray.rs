@@ -1,18 +1,23 @@
+use std::sync::Once;
+
 use glam::Vec3;
+use log::warn;
+
+use super::Rgb;
 
 #[derive(Clone, Copy)]
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
-    pub ior: f32,
+    pub media: MediumStack,
 }
 
 impl Ray {
-    pub fn new(origin: Vec3, direction: Vec3, ior: f32) -> Ray {
+    pub fn new(origin: Vec3, direction: Vec3, media: MediumStack) -> Ray {
         Ray {
             origin,
             direction,
-            ior,
+            media,
         }
     }
 
@@ -20,3 +25,100 @@ impl Ray {
         self.origin + self.direction * t
     }
 }
+
+//Volume a ray is inside of, `id` identifies the material that bounds it
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Medium {
+    pub id: usize,
+    pub ior: f32,
+    pub priority: u32,
+    //Fraction of light absorbed per unit of distance, for each channel
+    pub absorption: Vec3,
+}
+
+const MAX_MEDIA: usize = 8;
+
+//Media a ray is inside of, the one with the highest priority is the one the ray travels through
+//Based on [https://doi.org/10.1080/10867651.2002.10487555]
+#[derive(Clone, Copy, Debug)]
+pub struct MediumStack {
+    media: [Medium; MAX_MEDIA],
+    len: usize,
+}
+
+impl MediumStack {
+    //Rays start in air
+    pub const EMPTY: MediumStack = MediumStack {
+        media: [Medium {
+            id: 0,
+            ior: 1.0,
+            priority: 0,
+            absorption: Vec3::ZERO,
+        }; MAX_MEDIA],
+        len: 0,
+    };
+
+    //Highest priority medium, the most recently entered one on ties
+    pub fn current(&self) -> Option<&Medium> {
+        self.media[..self.len]
+            .iter()
+            .rev()
+            .reduce(|current, medium| {
+                if medium.priority > current.priority {
+                    medium
+                } else {
+                    current
+                }
+            })
+    }
+
+    pub fn ior(&self) -> f32 {
+        self.current().map_or(1.0, |medium| medium.ior)
+    }
+
+    //Light left after travelling `distance` through the current medium
+    //Beer-Lambert law [https://en.wikipedia.org/wiki/Beer%E2%80%93Lambert_law]
+    pub fn transmittance(&self, distance: f32) -> Rgb<f32> {
+        let absorption = self
+            .current()
+            .map_or(Vec3::ZERO, |medium| medium.absorption);
+
+        //Non absorbing channels stay lit even for rays escaping to infinity
+        Rgb(absorption
+            .to_array()
+            .map(|a| if a > 0.0 { (-a * distance).exp() } else { 1.0 }))
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.media[..self.len].iter().any(|medium| medium.id == id)
+    }
+
+    //Deeper nesting than `MAX_MEDIA` is ignored
+    pub fn with(&self, medium: Medium) -> MediumStack {
+        let mut stack = *self;
+        if stack.len < MAX_MEDIA {
+            stack.media[stack.len] = medium;
+            stack.len += 1;
+        } else {
+            static OVERFLOW_WARNING: Once = Once::new();
+            OVERFLOW_WARNING.call_once(|| {
+                warn!(
+                    "Volumes are nested more than {MAX_MEDIA} deep, the innermost ones are ignored"
+                )
+            });
+        }
+        stack
+    }
+
+    pub fn without(&self, id: usize) -> MediumStack {
+        let mut stack = *self;
+        if let Some(i) = stack.media[..stack.len]
+            .iter()
+            .rposition(|medium| medium.id == id)
+        {
+            stack.media.copy_within(i + 1..stack.len, i);
+            stack.len -= 1;
+        }
+        stack
+    }
+}
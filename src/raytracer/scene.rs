@@ -0,0 +1,642 @@
+use std::{
+    collections::HashMap,
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Context, Result};
+use glam::{Vec2, Vec3};
+use serde::Deserialize;
+use toml::Spanned;
+
+use super::{
+    bvh::Aabb,
+    camera::Camera,
+    gltf::GltfModel,
+    material::{
+        DiffuseMaterial, EmissiveMaterial, MetalMaterial, PrincipledMaterial, RaytraceMaterial,
+        TransparentMaterial,
+    },
+    obj::ObjModel,
+    shape::{triangle_bounding_box, Mesh, Sphere, Triangle, TriangleMesh},
+    world::World,
+    Raytrace, Rgb,
+};
+
+const DEFAULT_SCENE: &str = include_str!("../../scenes/default.toml");
+
+//File layout
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFile {
+    camera: Spanned<CameraDescription>,
+    #[serde(default)]
+    materials: HashMap<String, Spanned<MaterialDescription>>,
+    #[serde(default)]
+    objects: Vec<Spanned<ObjectDescription>>,
+}
+
+//Every field of the file with where it is written, used to point errors at the offending value
+//The descriptions can't hold spans themselves as serde buffers the content of tagged enums
+type FieldSpans = HashMap<String, Spanned<toml::Value>>;
+
+#[derive(Deserialize)]
+struct SceneFileSpans {
+    #[serde(default)]
+    camera: FieldSpans,
+    #[serde(default)]
+    materials: HashMap<String, FieldSpans>,
+    #[serde(default)]
+    objects: Vec<FieldSpans>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDescription {
+    position: Vec3,
+    look_at: Vec3,
+    #[serde(default = "default_camera_up")]
+    up: Vec3,
+    //Degrees
+    #[serde(default)]
+    roll: f32,
+    //Width of the viewport, `fov` replaces it
+    size: Option<f32>,
+    //Vertical, in degrees
+    fov: Option<f32>,
+    #[serde(default = "default_focal_length")]
+    focal_length: f32,
+    #[serde(default)]
+    aperture: f32,
+    //Defaults to the distance to `look_at`
+    focus_distance: Option<f32>,
+}
+
+fn default_camera_up() -> Vec3 {
+    Vec3::Y
+}
+
+fn default_camera_size() -> f32 {
+    2.0
+}
+
+fn default_focal_length() -> f32 {
+    1.0
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum MaterialDescription {
+    Diffuse {
+        color: Rgb<u8>,
+    },
+    Metal {
+        color: Rgb<u8>,
+        #[serde(default)]
+        roughness: f32,
+    },
+    Transparent {
+        color: Rgb<u8>,
+        ior: f32,
+        #[serde(default)]
+        priority: u32,
+        #[serde(default)]
+        absorption: Vec3,
+    },
+    Emissive {
+        color: Rgb<u8>,
+        #[serde(default = "default_strength")]
+        strength: f32,
+    },
+    Principled {
+        color: Rgb<u8>,
+        #[serde(default)]
+        metallic: f32,
+        #[serde(default = "default_half")]
+        roughness: f32,
+        #[serde(default = "default_half")]
+        specular: f32,
+        #[serde(default)]
+        transmission: f32,
+        #[serde(default = "default_ior")]
+        ior: f32,
+        #[serde(default)]
+        clearcoat: f32,
+        #[serde(default)]
+        sheen: f32,
+    },
+}
+
+fn default_strength() -> f32 {
+    1.0
+}
+
+fn default_half() -> f32 {
+    0.5
+}
+
+fn default_ior() -> f32 {
+    1.5
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+enum ObjectDescription {
+    Sphere {
+        position: Vec3,
+        radius: f32,
+        material: String,
+    },
+    Triangle {
+        vertices: [Vec3; 3],
+        normals: Option<[Vec3; 3]>,
+        uvs: Option<[Vec2; 3]>,
+        material: String,
+    },
+    Mesh {
+        positions: Vec<Vec3>,
+        indices: Vec<[u32; 3]>,
+        normals: Option<Vec<Vec3>>,
+        uvs: Option<Vec<Vec2>>,
+        material: String,
+    },
+    Obj {
+        path: PathBuf,
+        material: Option<String>,
+    },
+    Gltf {
+        path: PathBuf,
+        material: Option<String>,
+    },
+}
+
+//Loaded scene
+pub enum Shape {
+    Sphere {
+        position: Vec3,
+        radius: f32,
+    },
+    Triangle {
+        vertices: [Vec3; 3],
+        normals: Option<[Vec3; 3]>,
+        uvs: Option<[Vec2; 3]>,
+    },
+    Mesh(Arc<Mesh>),
+}
+
+impl Shape {
+    pub fn bounding_box(&self) -> Aabb {
+        match self {
+            Shape::Sphere { position, radius } => Aabb::new(
+                *position - Vec3::splat(*radius),
+                *position + Vec3::splat(*radius),
+            ),
+            Shape::Triangle { vertices, .. } => triangle_bounding_box(vertices),
+            Shape::Mesh(mesh) => mesh.bounding_box(),
+        }
+    }
+}
+
+pub struct SceneObject {
+    pub shape: Shape,
+    pub material: usize,
+}
+
+pub struct Scene {
+    pub camera: Camera,
+    pub materials: Vec<MaterialDescription>,
+    pub objects: Vec<SceneObject>,
+}
+
+impl Scene {
+    //Loads a TOML scene file, or renders a glTF file directly
+    pub fn load(path: &Path) -> Result<Self> {
+        if let Some("gltf" | "glb") = path.extension().and_then(|extension| extension.to_str()) {
+            return Self::from_gltf(path);
+        }
+
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read scene file {}", path.display()))?;
+
+        let directory = path.parent().unwrap_or(Path::new(""));
+        Self::parse(&source, directory)
+            .with_context(|| format!("Failed to load scene file {}", path.display()))
+    }
+
+    //Paths in the scene are relative to `directory`
+    pub fn parse(source: &str, directory: &Path) -> Result<Self> {
+        let file: SceneFile = toml::from_str(source)?;
+        let mut spans: SceneFileSpans = toml::from_str(source)?;
+
+        //Points at `field` of the table starting at `table_span`, or at the table when the field isn't written
+        let error_at = |table_span: &Range<usize>,
+                        fields: &FieldSpans,
+                        path: &str,
+                        field: &str,
+                        message: String| {
+            let span = fields.get(field).map_or(table_span.clone(), Spanned::span);
+            anyhow!(
+                "line {}, field `{path}.{field}`: {message}",
+                source[..span.start].matches('\n').count() + 1
+            )
+        };
+
+        //Camera
+        let camera_span = file.camera.span();
+        let camera = file.camera.into_inner();
+        let camera_error = |field: &str, message: String| {
+            error_at(&camera_span, &spans.camera, "camera", field, message)
+        };
+        if camera.position == camera.look_at {
+            return Err(camera_error(
+                "look_at",
+                "must differ from the camera position".to_string(),
+            ));
+        }
+        if let Some(size) = camera.size.filter(|&size| size <= 0.0) {
+            return Err(camera_error(
+                "size",
+                format!("must be positive, got {size}"),
+            ));
+        }
+        if camera.focal_length <= 0.0 {
+            return Err(camera_error(
+                "focal_length",
+                format!("must be positive, got {}", camera.focal_length),
+            ));
+        }
+        if camera.size.is_some() && camera.fov.is_some() {
+            return Err(camera_error(
+                "fov",
+                "can't be set along with size".to_string(),
+            ));
+        }
+        if let Some(fov) = camera.fov.filter(|&fov| fov <= 0.0 || fov >= 180.0) {
+            return Err(camera_error(
+                "fov",
+                format!("must be between 0 and 180 degrees, got {fov}"),
+            ));
+        }
+        if camera.up == Vec3::ZERO {
+            return Err(camera_error("up", "must not be zero".to_string()));
+        }
+        if camera.aperture < 0.0 {
+            return Err(camera_error(
+                "aperture",
+                format!("must not be negative, got {}", camera.aperture),
+            ));
+        }
+        if let Some(focus_distance) = camera.focus_distance.filter(|&d| d <= 0.0) {
+            return Err(camera_error(
+                "focus_distance",
+                format!("must be positive, got {focus_distance}"),
+            ));
+        }
+
+        //Materials, sorted by name so indices are stable between loads
+        let mut named_materials: Vec<_> = file.materials.into_iter().collect();
+        named_materials.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut material_indices = HashMap::new();
+        let mut materials = Vec::with_capacity(named_materials.len());
+        for (name, material) in named_materials {
+            let fields = spans.materials.remove(&name).unwrap_or_default();
+            let material_error = |field: &str, message: String| {
+                error_at(
+                    &material.span(),
+                    &fields,
+                    &format!("materials.{name}"),
+                    field,
+                    message,
+                )
+            };
+
+            match material.get_ref() {
+                MaterialDescription::Transparent { ior, .. }
+                | MaterialDescription::Principled { ior, .. }
+                    if *ior <= 0.0 =>
+                {
+                    return Err(material_error(
+                        "ior",
+                        format!("must be positive, got {ior}"),
+                    ));
+                }
+                MaterialDescription::Metal { roughness, .. }
+                    if !(0.0..=1.0).contains(roughness) =>
+                {
+                    return Err(material_error(
+                        "roughness",
+                        format!("must be between 0 and 1, got {roughness}"),
+                    ));
+                }
+                MaterialDescription::Transparent { absorption, .. }
+                    if absorption.min_element() < 0.0 =>
+                {
+                    return Err(material_error(
+                        "absorption",
+                        format!("must not be negative, got {absorption}"),
+                    ));
+                }
+                MaterialDescription::Emissive { strength, .. } if *strength < 0.0 => {
+                    return Err(material_error(
+                        "strength",
+                        format!("must not be negative, got {strength}"),
+                    ));
+                }
+                MaterialDescription::Principled {
+                    metallic,
+                    roughness,
+                    specular,
+                    transmission,
+                    clearcoat,
+                    sheen,
+                    ..
+                } => {
+                    let factors = [
+                        ("metallic", metallic),
+                        ("roughness", roughness),
+                        ("specular", specular),
+                        ("transmission", transmission),
+                        ("clearcoat", clearcoat),
+                        ("sheen", sheen),
+                    ];
+                    if let Some((field, value)) = factors
+                        .into_iter()
+                        .find(|(_, value)| !(0.0..=1.0).contains(*value))
+                    {
+                        return Err(material_error(
+                            field,
+                            format!("must be between 0 and 1, got {value}"),
+                        ));
+                    }
+                }
+                _ => (),
+            }
+
+            material_indices.insert(name, materials.len());
+            materials.push(material.into_inner());
+        }
+
+        //Objects
+        let mut objects = Vec::with_capacity(file.objects.len());
+        //Materials of OBJ files, by file and material name
+        let mut obj_material_indices = HashMap::new();
+        for (i, object) in file.objects.into_iter().enumerate() {
+            let span = object.span();
+            let fields = spans
+                .objects
+                .get_mut(i)
+                .map(std::mem::take)
+                .unwrap_or_default();
+            let object_error = |field: &str, message: String| {
+                error_at(&span, &fields, &format!("objects[{i}]"), field, message)
+            };
+            let scene_material = |material: &String| {
+                material_indices.get(material).copied().ok_or_else(|| {
+                    object_error("material", format!("unknown material \"{material}\""))
+                })
+            };
+
+            match object.into_inner() {
+                ObjectDescription::Sphere {
+                    position,
+                    radius,
+                    material,
+                } => {
+                    if radius <= 0.0 {
+                        return Err(object_error(
+                            "radius",
+                            format!("must be positive, got {radius}"),
+                        ));
+                    }
+
+                    objects.push(SceneObject {
+                        shape: Shape::Sphere { position, radius },
+                        material: scene_material(&material)?,
+                    });
+                }
+                ObjectDescription::Triangle {
+                    vertices,
+                    normals,
+                    uvs,
+                    material,
+                } => objects.push(SceneObject {
+                    shape: Shape::Triangle {
+                        vertices,
+                        normals,
+                        uvs,
+                    },
+                    material: scene_material(&material)?,
+                }),
+                ObjectDescription::Mesh {
+                    positions,
+                    indices,
+                    normals,
+                    uvs,
+                    material,
+                } => {
+                    let mesh = Mesh {
+                        positions,
+                        normals,
+                        uvs,
+                        indices,
+                    };
+                    mesh.validate()
+                        .map_err(|(field, message)| object_error(field, message))?;
+
+                    objects.push(SceneObject {
+                        shape: Shape::Mesh(Arc::new(mesh)),
+                        material: scene_material(&material)?,
+                    });
+                }
+                //One mesh per OBJ material, `material` overrides the material libraries
+                ObjectDescription::Obj { path, material } => {
+                    let path = directory.join(path);
+                    let material = material.as_ref().map(scene_material).transpose()?;
+                    let model = ObjModel::load(&path, material.is_none())
+                        .map_err(|e| object_error("path", format!("{e:#}")))?;
+
+                    for group in model.groups {
+                        let material = match (material, group.material) {
+                            (Some(material), _) => material,
+                            (None, Some(name)) => *obj_material_indices
+                                .entry((path.clone(), name.clone()))
+                                .or_insert_with(|| {
+                                    materials.push(model.materials[&name].clone());
+                                    materials.len() - 1
+                                }),
+                            (None, None) => {
+                                return Err(object_error(
+                                    "material",
+                                    "required as some faces of the OBJ file have no material"
+                                        .to_string(),
+                                ))
+                            }
+                        };
+
+                        objects.push(SceneObject {
+                            shape: Shape::Mesh(Arc::new(group.mesh)),
+                            material,
+                        });
+                    }
+                }
+                //The scene camera is kept, `material` overrides the glTF materials
+                ObjectDescription::Gltf { path, material } => {
+                    let model = GltfModel::load(&directory.join(path))
+                        .map_err(|e| object_error("path", format!("{e:#}")))?;
+                    let material = material.as_ref().map(scene_material).transpose()?;
+
+                    let first_material = materials.len();
+                    if material.is_none() {
+                        materials.extend(model.materials);
+                    }
+
+                    objects.extend(model.meshes.into_iter().map(|mesh| SceneObject {
+                        shape: Shape::Mesh(Arc::new(mesh.mesh)),
+                        material: material.unwrap_or(first_material + mesh.material),
+                    }));
+                }
+            }
+        }
+
+        let mut scene_camera = Camera::new(
+            camera.position,
+            camera.size.unwrap_or_else(default_camera_size),
+            camera.look_at,
+            camera.focal_length,
+        );
+        scene_camera.set_orientation(camera.up, camera.roll.to_radians());
+        scene_camera.vertical_fov = camera.fov.map(f32::to_radians);
+        scene_camera.aperture = camera.aperture;
+        if let Some(focus_distance) = camera.focus_distance {
+            scene_camera.focus_distance = focus_distance;
+        }
+
+        Ok(Scene {
+            camera: scene_camera,
+            materials,
+            objects,
+        })
+    }
+
+    //Uses the first perspective camera of the file, or one framing the whole model
+    pub fn from_gltf(path: &Path) -> Result<Self> {
+        let mut model = GltfModel::load(path)?;
+        let camera = match model.camera.take() {
+            Some(camera) => camera,
+            None => model.framing_camera(),
+        };
+
+        let objects = model
+            .meshes
+            .into_iter()
+            .map(|mesh| SceneObject {
+                shape: Shape::Mesh(Arc::new(mesh.mesh)),
+                material: mesh.material,
+            })
+            .collect();
+
+        Ok(Scene {
+            camera,
+            materials: model.materials,
+            objects,
+        })
+    }
+
+    //Builds the objects traced by the CPU renderers
+    pub fn world(&self) -> World {
+        let objects = self
+            .objects
+            .iter()
+            .map(|object| -> Box<dyn Raytrace + Sync> {
+                let material = self.materials[object.material].build(object.material);
+                match &object.shape {
+                    Shape::Sphere { position, radius } => Box::new(Sphere {
+                        position: *position,
+                        radius: *radius,
+                        material,
+                    }),
+                    Shape::Triangle {
+                        vertices,
+                        normals,
+                        uvs,
+                    } => Box::new(Triangle {
+                        vertices: *vertices,
+                        normals: *normals,
+                        uvs: *uvs,
+                        material,
+                    }),
+                    Shape::Mesh(mesh) => Box::new(TriangleMesh::new(mesh.clone(), material)),
+                }
+            })
+            .collect();
+
+        World::new(objects)
+    }
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Self::parse(DEFAULT_SCENE, Path::new("")).expect("Default scene should be valid")
+    }
+}
+
+impl MaterialDescription {
+    //Emissive material from a linear radiance, split into a color and a strength
+    pub fn emissive(radiance: Vec3) -> Self {
+        let strength = radiance.max_element();
+        let color = radiance / strength * 255.0;
+
+        MaterialDescription::Emissive {
+            color: Rgb([color.x as u8, color.y as u8, color.z as u8]),
+            strength,
+        }
+    }
+
+    //`index` of the material in the scene, objects sharing it bound the same volume
+    pub fn build(&self, index: usize) -> Box<dyn RaytraceMaterial + Sync> {
+        match *self {
+            MaterialDescription::Diffuse { color } => Box::new(DiffuseMaterial { color }),
+            MaterialDescription::Metal { color, roughness } => {
+                Box::new(MetalMaterial { color, roughness })
+            }
+            MaterialDescription::Transparent {
+                color,
+                ior,
+                priority,
+                absorption,
+            } => Box::new(TransparentMaterial {
+                color,
+                ior,
+                priority,
+                absorption,
+                medium_id: index,
+            }),
+            MaterialDescription::Emissive { color, strength } => {
+                Box::new(EmissiveMaterial { color, strength })
+            }
+            MaterialDescription::Principled {
+                color,
+                metallic,
+                roughness,
+                specular,
+                transmission,
+                ior,
+                clearcoat,
+                sheen,
+            } => Box::new(PrincipledMaterial {
+                color,
+                metallic,
+                roughness,
+                specular,
+                transmission,
+                ior,
+                clearcoat,
+                sheen,
+                medium_id: index,
+            }),
+        }
+    }
+}
@@ -1,8 +1,11 @@
-use glam::Vec3;
+use std::{f32::consts::PI, sync::Arc};
+
+use glam::{Vec2, Vec3};
 
 use super::{
+    bvh::{Aabb, Bvh},
     material::{Material, RaytraceMaterial},
-    Ray, RayBounce, RayCast, RaycastHit, Raytrace, Rgb,
+    Bounce, BounceEvaluation, Ray, RayBounce, RayCast, RaycastHit, Raytrace, Rgb, SurfaceSample,
 };
 
 pub struct Sphere {
@@ -14,7 +17,7 @@ pub struct Sphere {
 impl Raytrace for Sphere {}
 
 impl RayCast for Sphere {
-    fn ray_cast(&self, ray: &Ray) -> Option<RaycastHit> {
+    fn ray_cast(&self, ray: &Ray) -> Option<RaycastHit<'_>> {
         let ray_sphere = self.position - ray.origin;
 
         let a = ray.direction.dot(ray.direction);
@@ -22,26 +25,71 @@ impl RayCast for Sphere {
         let c = ray_sphere.length_squared() - self.radius * self.radius;
 
         let discriminant = h * h - a * c;
-        let t = (h - discriminant.sqrt()) / a;
-        if discriminant < 0.0 || t < 0.001 {
+        if discriminant < 0.0 {
             return None;
         }
 
+        //Far intersection for rays starting inside the sphere
+        let t = [h - discriminant.sqrt(), h + discriminant.sqrt()]
+            .map(|root| root / a)
+            .into_iter()
+            .find(|&t| t >= 0.001)?;
+
         let point = ray.point_at(t);
         let normal = (point - self.position).normalize_or_zero();
 
+        //Spherical coordinates
+        let uv = Vec2::new(
+            (f32::atan2(-normal.z, normal.x) + PI) / (2.0 * PI),
+            f32::acos(-normal.y) / PI,
+        );
+
         Some(RaycastHit {
             distance: t,
             point,
             normal,
+            uv,
             object: self,
         })
     }
+
+    fn bounding_box(&self) -> Aabb {
+        Aabb::new(
+            self.position - Vec3::splat(self.radius),
+            self.position + Vec3::splat(self.radius),
+        )
+    }
+
+    fn surface_area(&self) -> f32 {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    fn sample_surface(&self) -> SurfaceSample {
+        //Uniform direction from the height and the angle around the axis
+        let z = 1.0 - 2.0 * fastrand::f32();
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * fastrand::f32();
+        let normal = Vec3::new(r * phi.cos(), r * phi.sin(), z);
+
+        SurfaceSample {
+            point: self.position + self.radius * normal,
+            normal,
+        }
+    }
 }
 
 impl RayBounce for Sphere {
-    fn ray_bounce(&self, incident: &Vec3, normal: &Vec3, position: &Vec3) -> Option<Ray> {
-        self.material.ray_bounce(incident, normal, position)
+    fn ray_bounce(&self, ray: &Ray, normal: &Vec3, position: &Vec3) -> Option<Bounce> {
+        self.material.ray_bounce(ray, normal, position)
+    }
+
+    fn evaluate_bounce(
+        &self,
+        incident: &Vec3,
+        normal: &Vec3,
+        direction: &Vec3,
+    ) -> Option<BounceEvaluation> {
+        self.material.evaluate_bounce(incident, normal, direction)
     }
 }
 
@@ -53,4 +101,377 @@ impl Material for Sphere {
     fn get_ior(&self) -> Option<f32> {
         self.material.get_ior()
     }
+
+    fn emitted(&self) -> Rgb<f32> {
+        self.material.emitted()
+    }
+}
+
+//Triangle
+pub struct Triangle {
+    pub vertices: [Vec3; 3],
+    pub normals: Option<[Vec3; 3]>,
+    pub uvs: Option<[Vec2; 3]>,
+    pub material: Box<dyn RaytraceMaterial + Sync>,
+}
+
+impl Raytrace for Triangle {}
+
+impl RayCast for Triangle {
+    fn ray_cast(&self, ray: &Ray) -> Option<RaycastHit<'_>> {
+        let (t, barycentric) = intersect_triangle(ray, &self.vertices)?;
+
+        Some(triangle_hit(
+            self,
+            ray,
+            t,
+            barycentric,
+            &self.vertices,
+            self.normals.as_ref(),
+            self.uvs.as_ref(),
+        ))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        triangle_bounding_box(&self.vertices)
+    }
+
+    fn surface_area(&self) -> f32 {
+        triangle_area(&self.vertices)
+    }
+
+    fn sample_surface(&self) -> SurfaceSample {
+        sample_triangle(&self.vertices)
+    }
+}
+
+impl RayBounce for Triangle {
+    fn ray_bounce(&self, ray: &Ray, normal: &Vec3, position: &Vec3) -> Option<Bounce> {
+        self.material.ray_bounce(ray, normal, position)
+    }
+
+    fn evaluate_bounce(
+        &self,
+        incident: &Vec3,
+        normal: &Vec3,
+        direction: &Vec3,
+    ) -> Option<BounceEvaluation> {
+        self.material.evaluate_bounce(incident, normal, direction)
+    }
+}
+
+impl Material for Triangle {
+    fn get_color(&self) -> Rgb<u8> {
+        self.material.get_color()
+    }
+
+    fn get_ior(&self) -> Option<f32> {
+        self.material.get_ior()
+    }
+
+    fn emitted(&self) -> Rgb<f32> {
+        self.material.emitted()
+    }
+}
+
+//Indexed triangle mesh geometry, `normals` and `uvs` are per vertex
+pub struct Mesh {
+    pub positions: Vec<Vec3>,
+    pub normals: Option<Vec<Vec3>>,
+    pub uvs: Option<Vec<Vec2>>,
+    pub indices: Vec<[u32; 3]>,
+}
+
+impl Mesh {
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn vertices(&self, triangle: usize) -> [Vec3; 3] {
+        self.indices[triangle].map(|i| self.positions[i as usize])
+    }
+
+    pub fn vertex_normals(&self, triangle: usize) -> Option<[Vec3; 3]> {
+        let normals = self.normals.as_ref()?;
+        Some(self.indices[triangle].map(|i| normals[i as usize]))
+    }
+
+    pub fn vertex_uvs(&self, triangle: usize) -> Option<[Vec2; 3]> {
+        let uvs = self.uvs.as_ref()?;
+        Some(self.indices[triangle].map(|i| uvs[i as usize]))
+    }
+
+    //Checks that indices and per vertex attributes match the positions, returns the faulty field
+    pub fn validate(&self) -> Result<(), (&'static str, String)> {
+        let vertex_count = self.positions.len();
+
+        if let Some(index) = self
+            .indices
+            .iter()
+            .flatten()
+            .find(|&&i| i as usize >= vertex_count)
+        {
+            return Err((
+                "indices",
+                format!("index {index} is out of range for {vertex_count} positions"),
+            ));
+        }
+        if let Some(normals) = &self.normals {
+            if normals.len() != vertex_count {
+                return Err((
+                    "normals",
+                    format!("expected {vertex_count} normals, got {}", normals.len()),
+                ));
+            }
+        }
+        if let Some(uvs) = &self.uvs {
+            if uvs.len() != vertex_count {
+                return Err((
+                    "uvs",
+                    format!("expected {vertex_count} uvs, got {}", uvs.len()),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn bounding_box(&self) -> Aabb {
+        self.positions
+            .iter()
+            .fold(Aabb::EMPTY, |acc, &position| acc.grow(position))
+    }
+}
+
+//Triangle mesh, accelerated by its own BVH
+pub struct TriangleMesh {
+    mesh: Arc<Mesh>,
+    bvh: Bvh,
+    //Running sum of the triangle areas, used to sample triangles proportionally to their area
+    area_sums: Vec<f32>,
+    pub material: Box<dyn RaytraceMaterial + Sync>,
+}
+
+impl TriangleMesh {
+    pub fn new(mesh: Arc<Mesh>, material: Box<dyn RaytraceMaterial + Sync>) -> Self {
+        let bounds: Vec<Aabb> = (0..mesh.triangle_count())
+            .map(|i| triangle_bounding_box(&mesh.vertices(i)))
+            .collect();
+        let bvh = Bvh::new(&bounds);
+
+        let area_sums = (0..mesh.triangle_count())
+            .scan(0.0, |sum, i| {
+                *sum += triangle_area(&mesh.vertices(i));
+                Some(*sum)
+            })
+            .collect();
+
+        Self {
+            mesh,
+            bvh,
+            area_sums,
+            material,
+        }
+    }
+}
+
+impl Raytrace for TriangleMesh {}
+
+impl RayCast for TriangleMesh {
+    fn ray_cast(&self, ray: &Ray) -> Option<RaycastHit<'_>> {
+        self.bvh.ray_cast(ray, |i| {
+            let vertices = self.mesh.vertices(i);
+            let (t, barycentric) = intersect_triangle(ray, &vertices)?;
+
+            Some(triangle_hit(
+                self,
+                ray,
+                t,
+                barycentric,
+                &vertices,
+                self.mesh.vertex_normals(i).as_ref(),
+                self.mesh.vertex_uvs(i).as_ref(),
+            ))
+        })
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.mesh.bounding_box()
+    }
+
+    fn surface_area(&self) -> f32 {
+        self.area_sums.last().copied().unwrap_or(0.0)
+    }
+
+    fn sample_surface(&self) -> SurfaceSample {
+        let target = fastrand::f32() * self.surface_area();
+        let triangle = self
+            .area_sums
+            .partition_point(|&sum| sum <= target)
+            .min(self.area_sums.len().saturating_sub(1));
+
+        sample_triangle(&self.mesh.vertices(triangle))
+    }
+}
+
+impl RayBounce for TriangleMesh {
+    fn ray_bounce(&self, ray: &Ray, normal: &Vec3, position: &Vec3) -> Option<Bounce> {
+        self.material.ray_bounce(ray, normal, position)
+    }
+
+    fn evaluate_bounce(
+        &self,
+        incident: &Vec3,
+        normal: &Vec3,
+        direction: &Vec3,
+    ) -> Option<BounceEvaluation> {
+        self.material.evaluate_bounce(incident, normal, direction)
+    }
+}
+
+impl Material for TriangleMesh {
+    fn get_color(&self) -> Rgb<u8> {
+        self.material.get_color()
+    }
+
+    fn get_ior(&self) -> Option<f32> {
+        self.material.get_ior()
+    }
+
+    fn emitted(&self) -> Rgb<f32> {
+        self.material.emitted()
+    }
+}
+
+//Triangle utility
+pub fn triangle_bounding_box(vertices: &[Vec3; 3]) -> Aabb {
+    vertices
+        .iter()
+        .fold(Aabb::EMPTY, |acc, &vertex| acc.grow(vertex))
+}
+
+pub fn triangle_area(vertices: &[Vec3; 3]) -> f32 {
+    0.5 * (vertices[1] - vertices[0])
+        .cross(vertices[2] - vertices[0])
+        .length()
+}
+
+//Uniform point on the triangle, with its geometric normal
+fn sample_triangle(vertices: &[Vec3; 3]) -> SurfaceSample {
+    let sqrt_u = fastrand::f32().sqrt();
+    let (b_0, b_1) = (1.0 - sqrt_u, fastrand::f32() * sqrt_u);
+
+    SurfaceSample {
+        point: b_0 * vertices[0] + b_1 * vertices[1] + (1.0 - b_0 - b_1) * vertices[2],
+        normal: (vertices[1] - vertices[0])
+            .cross(vertices[2] - vertices[0])
+            .normalize_or_zero(),
+    }
+}
+
+//Watertight ray/triangle intersection, returns the distance and the barycentric coordinates
+//Algorithm from [https://jcgt.org/published/0002/01/05/]
+pub fn intersect_triangle(ray: &Ray, vertices: &[Vec3; 3]) -> Option<(f32, Vec3)> {
+    let direction = ray.direction;
+
+    //Permute axes so that the direction is mostly along z
+    let abs_direction = direction.abs();
+    let kz = if abs_direction.x > abs_direction.y && abs_direction.x > abs_direction.z {
+        0
+    } else if abs_direction.y > abs_direction.z {
+        1
+    } else {
+        2
+    };
+    let mut kx = (kz + 1) % 3;
+    let mut ky = (kx + 1) % 3;
+    if direction[kz] < 0.0 {
+        std::mem::swap(&mut kx, &mut ky);
+    }
+
+    //Shear so that the ray goes along +z
+    let shear_x = direction[kx] / direction[kz];
+    let shear_y = direction[ky] / direction[kz];
+    let shear_z = 1.0 / direction[kz];
+
+    let [a, b, c] = vertices.map(|vertex| vertex - ray.origin);
+    let sheared = |p: Vec3| (p[kx] - shear_x * p[kz], p[ky] - shear_y * p[kz]);
+    let (a_x, a_y) = sheared(a);
+    let (b_x, b_y) = sheared(b);
+    let (c_x, c_y) = sheared(c);
+
+    //Scaled barycentric coordinates
+    let mut u = c_x * b_y - c_y * b_x;
+    let mut v = a_x * c_y - a_y * c_x;
+    let mut w = b_x * a_y - b_y * a_x;
+
+    //Recompute with double precision on edges
+    if u == 0.0 || v == 0.0 || w == 0.0 {
+        let [a_x, a_y, b_x, b_y, c_x, c_y] = [a_x, a_y, b_x, b_y, c_x, c_y].map(f64::from);
+        u = (c_x * b_y - c_y * b_x) as f32;
+        v = (a_x * c_y - a_y * c_x) as f32;
+        w = (b_x * a_y - b_y * a_x) as f32;
+    }
+
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+
+    let determinant = u + v + w;
+    if determinant == 0.0 {
+        return None;
+    }
+
+    let scaled_t = u * shear_z * a[kz] + v * shear_z * b[kz] + w * shear_z * c[kz];
+
+    let t = scaled_t / determinant;
+    if t < 0.001 {
+        return None;
+    }
+
+    Some((t, Vec3::new(u, v, w) / determinant))
+}
+
+fn triangle_hit<'a>(
+    object: &'a dyn Raytrace,
+    ray: &Ray,
+    t: f32,
+    barycentric: Vec3,
+    vertices: &[Vec3; 3],
+    normals: Option<&[Vec3; 3]>,
+    uvs: Option<&[Vec2; 3]>,
+) -> RaycastHit<'a> {
+    let geometric_normal = (vertices[1] - vertices[0])
+        .cross(vertices[2] - vertices[0])
+        .normalize_or_zero();
+
+    //Shading normal, kept on the side of the geometric normal
+    let normal = match normals {
+        Some(normals) => {
+            let normal = (barycentric.x * normals[0]
+                + barycentric.y * normals[1]
+                + barycentric.z * normals[2])
+                .normalize_or_zero();
+
+            if normal.dot(geometric_normal) < 0.0 {
+                -normal
+            } else {
+                normal
+            }
+        }
+        None => geometric_normal,
+    };
+
+    let uv = match uvs {
+        Some(uvs) => barycentric.x * uvs[0] + barycentric.y * uvs[1] + barycentric.z * uvs[2],
+        None => Vec2::new(barycentric.y, barycentric.z),
+    };
+
+    RaycastHit {
+        distance: t,
+        point: ray.point_at(t),
+        normal,
+        uv,
+        object,
+    }
 }
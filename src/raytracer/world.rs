@@ -0,0 +1,154 @@
+use super::{
+    bvh::{Aabb, Bvh},
+    ray::Ray,
+    RaycastHit, Raytrace,
+};
+
+//Objects traced by the CPU renderers, accelerated by a BVH
+pub struct World {
+    objects: Vec<Box<dyn Raytrace + Sync>>,
+    bvh: Bvh,
+    //Indices of the emissive objects
+    lights: Vec<usize>,
+}
+
+impl World {
+    pub fn new(objects: Vec<Box<dyn Raytrace + Sync>>) -> Self {
+        let bounds: Vec<Aabb> = objects.iter().map(|object| object.bounding_box()).collect();
+        let bvh = Bvh::new(&bounds);
+
+        let lights = objects
+            .iter()
+            .enumerate()
+            .filter(|(_, object)| {
+                object.emitted().iter().any(|&c| c > 0.0) && object.surface_area() > 0.0
+            })
+            .map(|(i, _)| i)
+            .collect();
+
+        Self {
+            objects,
+            bvh,
+            lights,
+        }
+    }
+
+    pub fn objects(&self) -> &[Box<dyn Raytrace + Sync>] {
+        &self.objects
+    }
+
+    //Uniformly picks a light, returns it with the probability of picking it
+    pub fn pick_light(&self) -> Option<(&(dyn Raytrace + Sync), f32)> {
+        if self.lights.is_empty() {
+            return None;
+        }
+
+        let light = self.lights[fastrand::usize(..self.lights.len())];
+        Some((self.objects[light].as_ref(), 1.0 / self.lights.len() as f32))
+    }
+
+    //Solid angle pdf of light sampling choosing the point hit by `ray` on `light`
+    pub fn light_pdf(&self, light: &dyn Raytrace, ray: &Ray, hit: &RaycastHit) -> f32 {
+        if self.lights.is_empty()
+            || light.emitted().iter().all(|&c| c <= 0.0)
+            || light.surface_area() <= 0.0
+        {
+            return 0.0;
+        }
+
+        let to_light = hit.point - ray.origin;
+        let light_cosine = hit.normal.dot(to_light.normalize_or_zero()).abs();
+        if light_cosine <= 0.0 {
+            return 0.0;
+        }
+
+        to_light.length_squared() / (light_cosine * light.surface_area() * self.lights.len() as f32)
+    }
+
+    pub fn ray_cast(&self, ray: &Ray) -> Option<RaycastHit<'_>> {
+        self.bvh.ray_cast(ray, |i| self.objects[i].ray_cast(ray))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec3;
+
+    use super::{
+        super::{
+            bvh::MAX_LEAF_SIZE, material::DiffuseMaterial, ray::MediumStack, shape::Sphere, Rgb,
+        },
+        *,
+    };
+
+    fn sphere(position: Vec3, radius: f32) -> Box<dyn Raytrace + Sync> {
+        Box::new(Sphere {
+            position,
+            radius,
+            material: Box::new(DiffuseMaterial {
+                color: Rgb([255, 255, 255]),
+            }),
+        })
+    }
+
+    fn random_vector(scale: f32) -> Vec3 {
+        (Vec3::new(fastrand::f32(), fastrand::f32(), fastrand::f32()) * 2.0 - 1.0) * scale
+    }
+
+    //Closest hit found without the BVH
+    fn linear_ray_cast<'a>(world: &'a World, ray: &Ray) -> Option<RaycastHit<'a>> {
+        world
+            .objects()
+            .iter()
+            .filter_map(|object| object.ray_cast(ray))
+            .min_by(|a, b| a.distance.total_cmp(&b.distance))
+    }
+
+    fn assert_matches_linear_scan(world: &World, ray_count: usize) {
+        for _ in 0..ray_count {
+            let ray = Ray::new(
+                random_vector(12.0),
+                random_vector(1.0).normalize_or(Vec3::X),
+                MediumStack::EMPTY,
+            );
+
+            match (world.ray_cast(&ray), linear_ray_cast(world, &ray)) {
+                (None, None) => {}
+                (Some(hit), Some(expected)) => {
+                    assert_eq!(hit.distance, expected.distance);
+                    assert!(std::ptr::addr_eq(hit.object, expected.object));
+                }
+                (hit, expected) => panic!(
+                    "BVH hit {:?} but linear scan hit {:?}",
+                    hit.map(|hit| hit.distance),
+                    expected.map(|hit| hit.distance)
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn bvh_matches_linear_scan() {
+        fastrand::seed(5);
+        for object_count in [1, 2, 7, 50, 300] {
+            let objects = (0..object_count)
+                .map(|_| sphere(random_vector(10.0), 0.1 + fastrand::f32()))
+                .collect();
+            assert_matches_linear_scan(&World::new(objects), 500);
+        }
+    }
+
+    #[test]
+    fn bvh_matches_linear_scan_with_identical_centroids() {
+        fastrand::seed(5);
+        //Concentric spheres can't be split by centroid, each ray still hits a single closest one
+        for other_count in [0, 20] {
+            let center = random_vector(2.0);
+            let objects = (0..MAX_LEAF_SIZE * 4)
+                .map(|i| sphere(center, 0.5 + i as f32 * 0.5))
+                .chain((0..other_count).map(|_| sphere(random_vector(10.0), 0.5)))
+                .collect();
+            assert_matches_linear_scan(&World::new(objects), 1000);
+        }
+    }
+}
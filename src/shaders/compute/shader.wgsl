@@ -3,12 +3,24 @@ struct Args {
     width: u32,
     height: u32,
     samples: u32,
+    bvh_node_count: u32,
+    max_bounces: u32,
+    //Samples averaged since the last reset
+    accumulated_samples: u32,
 };
 
 struct Material {
-    mat_type: u32,
     color: vec3<f32>,
-    ior: f32
+    mat_type: u32,
+    emission: vec3<f32>,
+    ior: f32,
+    absorption: vec3<f32>,
+    roughness: f32,
+    metallic: f32,
+    specular: f32,
+    transmission: f32,
+    clearcoat: f32,
+    sheen: f32,
 }
 
 struct Viewport {
@@ -24,10 +36,15 @@ struct Viewport {
 
 struct Camera {
     position: vec3<f32>,
-    up: vec3<f32>,
+    size: f32,
     forward: vec3<f32>,
+    focal_length: f32,
     right: vec3<f32>,
-    focal_length: f32
+    aperture: f32,
+    up: vec3<f32>,
+    focus_distance: f32,
+    //Radians, 0 when `size` is used instead
+    vertical_fov: f32,
 }
 
 struct Ray {
@@ -38,7 +55,35 @@ struct Ray {
 struct Sphere {
     position: vec3<f32>,
     radius: f32,
-    material: Material,
+    material: u32,
+}
+
+struct Triangle {
+    vertices: array<vec3<f32>, 3>,
+    normals: array<vec3<f32>, 3>,
+    material: u32,
+}
+
+//Index into `world` or `triangles` depending on `kind`
+struct Primitive {
+    kind: u32,
+    index: u32,
+}
+
+//Depth first flattened node, `skip` is the next node when the subtree is not entered
+//Interior nodes have a `count` of 0 and their left child right after them
+struct BvhNode {
+    min: vec3<f32>,
+    skip: u32,
+    max: vec3<f32>,
+    first: u32,
+    count: u32,
+}
+
+//Direction leaving a surface, absorbed rays have no `attenuation`
+struct Bounce {
+    direction: vec3<f32>,
+    attenuation: vec3<f32>,
 }
 
 struct RaycastHit {
@@ -54,6 +99,20 @@ struct RaycastHit {
 var<uniform> args: Args; 
 @group(0) @binding(1) 
 var output_texture: texture_storage_2d<rgba8unorm, write>;
+@group(0) @binding(2)
+var<uniform> camera: Camera;
+@group(0) @binding(3)
+var<storage, read> materials: array<Material>;
+@group(0) @binding(4)
+var<storage, read> world: array<Sphere>;
+@group(0) @binding(5)
+var<storage, read> bvh: array<BvhNode>;
+@group(0) @binding(6)
+var<storage, read> triangles: array<Triangle>;
+@group(0) @binding(7)
+var<storage, read> primitives: array<Primitive>;
+@group(0) @binding(8)
+var<storage, read_write> accumulation: array<vec4<f32>>;
 
 //Utils
 //https://gist.github.com/munrocket/236ed5ba7e409b8bdf1ff6eca5dcdc39
@@ -70,68 +129,227 @@ fn pcg3d(p: vec3<u32>) -> vec3<u32> {
     return v;
 }
 
-//State
-const CAMERA_POSITION = vec3<f32>(0.0, 0.0, 1.0);
-const CAMERA_LOOK_AT = vec3<f32>(0.0, 0.0, 0.0);
-const CAMERA_FOCAL_LENGTH = 1.0;
+//Uniform floats in [0, 1) from the 24 high bits of the hash
+fn random_floats(seed: vec3<u32>) -> vec3<f32> {
+    return vec3<f32>(pcg3d(seed) >> vec3<u32>(8u)) / 16777216.0;
+}
+
+//Columns are a tangent, a bitangent and `normal`
+//Based on : https://jcgt.org/published/0006/01/01/
+fn orthonormal_basis(normal: vec3<f32>) -> mat3x3<f32> {
+    let s = select(-1.0, 1.0, normal.z >= 0.0);
+    let a = -1.0 / (s + normal.z);
+    let b = normal.x * normal.y * a;
+    let tangent = vec3<f32>(1.0 + s * normal.x * normal.x * a, s * b, -s * normal.x);
+    let bitangent = vec3<f32>(b, s + normal.y * normal.y * a, -normal.y);
+    return mat3x3<f32>(tangent, bitangent, normal);
+}
+
+//Mirrors material.rs
+fn fresnel_reflectance(cos_incident: f32, r: f32) -> f32 {
+    let sin_transmitted_sq = r * r * (1.0 - cos_incident * cos_incident);
+    if sin_transmitted_sq >= 1.0 { return 1.0; }
+    let cos_transmitted = sqrt(1.0 - sin_transmitted_sq);
+
+    let r_s = (r * cos_incident - cos_transmitted) / (r * cos_incident + cos_transmitted);
+    let r_p = (cos_incident - r * cos_transmitted) / (cos_incident + r * cos_transmitted);
+
+    return 0.5 * (r_s * r_s + r_p * r_p);
+}
+
+//Reflects or refracts with the probability given by the Fresnel equations, `normal` faces the incident side
+fn dielectric_direction(incident: vec3<f32>, normal: vec3<f32>, ior_ratio: f32, u: f32) -> vec3<f32> {
+    let direction = normalize(incident);
+    let refracted = refract(direction, normal, ior_ratio);
+    if u < fresnel_reflectance(-dot(direction, normal), ior_ratio) || all(refracted == vec3<f32>(0.0))
+    {
+        return reflect(direction, normal);
+    }
+    return refracted;
+}
+
+fn schlick_weight(cos_incident: f32) -> f32 {
+    return pow(1.0 - clamp(cos_incident, 0.0, 1.0), 5.0);
+}
+
+fn schlick_reflectance(color: vec3<f32>, cos_incident: f32) -> vec3<f32> {
+    return mix(color, vec3<f32>(1.0), schlick_weight(cos_incident));
+}
+
+fn ggx_lambda(cos: f32, alpha: f32) -> f32 {
+    let cos_sq = cos * cos;
+    let tan_sq = max(1.0 - cos_sq, 0.0) / cos_sq;
+    return (sqrt(1.0 + alpha * alpha * tan_sq) - 1.0) / 2.0;
+}
+
+//`G2 / G1(view)`, the weight of a reflection off a visible normal sample
+fn ggx_masking(cos_view: f32, cos_out: f32, alpha: f32) -> f32 {
+    let lambda_view = ggx_lambda(cos_view, alpha);
+    return (1.0 + lambda_view) / (1.0 + lambda_view + ggx_lambda(cos_out, alpha));
+}
 
-const GLOBAL_UP = vec3<f32>(0.0, 1.0, 0.0);
+//Visible normal sampling in the frame where the normal is +z
+//Based on : https://jcgt.org/published/0007/04/01/
+fn sample_ggx_visible_normal(view: vec3<f32>, alpha: f32, u: vec2<f32>) -> vec3<f32> {
+    let stretched = normalize(vec3<f32>(alpha * view.xy, view.z));
 
-const MAX_BOUNCE: u32 = 4u;
+    let length_sq = dot(stretched.xy, stretched.xy);
+    var t1 = vec3<f32>(1.0, 0.0, 0.0);
+    if length_sq > 0.0 { t1 = vec3<f32>(-stretched.y, stretched.x, 0.0) / sqrt(length_sq); }
+    let t2 = cross(stretched, t1);
+
+    let r = sqrt(u.x);
+    let phi = 2.0 * PI * u.y;
+    let p1 = r * cos(phi);
+    let s = 0.5 * (1.0 + stretched.z);
+    let p2 = (1.0 - s) * sqrt(1.0 - p1 * p1) + s * r * sin(phi);
+
+    let normal = p1 * t1 + p2 * t2 + sqrt(max(1.0 - p1 * p1 - p2 * p2, 0.0)) * stretched;
+    return normalize(vec3<f32>(alpha * normal.xy, max(normal.z, 0.0)));
+}
+
+//Cosine weighted direction in the frame where the normal is +z
+fn sample_cosine_direction(u: vec2<f32>) -> vec3<f32> {
+    let r = sqrt(u.x);
+    let phi = 2.0 * PI * u.y;
+    return vec3<f32>(r * cos(phi), r * sin(phi), sqrt(1.0 - u.x));
+}
+
+//Principled
+const CLEARCOAT_ALPHA: f32 = 0.01;
+const CLEARCOAT_F0: f32 = 0.04;
+
+fn principled_clearcoat_reflectance(material: Material, cos: f32) -> f32 {
+    return material.clearcoat * (CLEARCOAT_F0 + (1.0 - CLEARCOAT_F0) * schlick_weight(cos));
+}
+
+fn principled_dielectric_reflectance(material: Material, cos: f32) -> f32 {
+    let f0 = 0.08 * material.specular;
+    return f0 + (1.0 - f0) * schlick_weight(cos);
+}
+
+fn principled_specular_reflectance(material: Material, cos: f32) -> vec3<f32> {
+    let opaque = (1.0 - material.metallic) * (1.0 - material.transmission);
+    return schlick_reflectance(material.color, cos) * material.metallic + principled_dielectric_reflectance(material, cos) * opaque;
+}
+
+//Picks a lobe in proportion to the energy it reflects towards the viewer, the sample is weighted by that lobe alone
+fn principled_bounce(material: Material, incident: vec3<f32>, normal: vec3<f32>, u: vec3<f32>) -> Bounce {
+    let view = -normalize(incident);
+
+    //Rays that entered the glass only see its boundary
+    if dot(view, normal) < 0.0 && material.transmission > 0.0
+    {
+        return Bounce(dielectric_direction(incident, -normal, material.ior, u.x), vec3<f32>(1.0));
+    }
+
+    let front_normal = faceForward(normal, incident, normal);
+    let cos_view = dot(view, front_normal);
+    let basis = orthonormal_basis(front_normal);
+
+    let clearcoat = principled_clearcoat_reflectance(material, cos_view);
+    let base = 1.0 - clearcoat;
+    let opaque = (1.0 - material.metallic) * (1.0 - material.transmission);
+    let glass = (1.0 - material.metallic) * material.transmission;
+    var lobes = vec4<f32>(
+        clearcoat,
+        base * dot(principled_specular_reflectance(material, cos_view), vec3<f32>(1.0 / 3.0)),
+        base * opaque * (1.0 - principled_dielectric_reflectance(material, cos_view)),
+        base * glass
+    );
+    let total = dot(lobes, vec4<f32>(1.0));
+    if total > 0.0 { lobes /= total; } else { lobes = vec4<f32>(0.0, 1.0, 0.0, 0.0); }
+
+    if u.x < lobes.x + lobes.y
+    {
+        var alpha = CLEARCOAT_ALPHA;
+        if u.x >= lobes.x { alpha = max(material.roughness * material.roughness, 1e-3); }
+
+        let half = basis * sample_ggx_visible_normal(view * basis, alpha, u.yz);
+        let direction = reflect(-view, half);
+        let cos_out = dot(direction, front_normal);
+        if cos_out <= 0.0 { return Bounce(direction, vec3<f32>(0.0)); }
+
+        let masking = ggx_masking(cos_view, cos_out, alpha);
+        if u.x < lobes.x
+        {
+            return Bounce(direction, vec3<f32>(principled_clearcoat_reflectance(material, dot(view, half)) * masking / lobes.x));
+        }
+        return Bounce(direction, base * principled_specular_reflectance(material, dot(view, half)) * masking / lobes.y);
+    }
+
+    if u.x < lobes.x + lobes.y + lobes.z
+    {
+        let direction = basis * sample_cosine_direction(u.yz);
+        let sheen = PI * material.sheen * schlick_weight(dot(view, normalize(view + direction)));
+        let diffuse = material.color * (1.0 - principled_dielectric_reflectance(material, cos_view)) + sheen;
+        return Bounce(direction, base * opaque * diffuse / lobes.z);
+    }
+
+    //Glass is specular, its sample is only weighted by its share
+    let glass_u = (u.x - lobes.x - lobes.y - lobes.z) / max(lobes.w, 1e-6);
+    let direction = dielectric_direction(incident, front_normal, 1.0 / material.ior, glass_u);
+    return Bounce(direction, material.color * base * glass / max(lobes.w, 1e-6));
+}
+
+//State
+const RUSSIAN_ROULETTE_DEPTH: u32 = 3u;
+const PI: f32 = 3.14159265;
 
 const MAT_TYPE_DIFFUSE: u32 = 1u << 0u;
 const MAT_TYPE_METALIC: u32 = 1u << 1u;
 const MAT_TYPE_TRANSPARENT: u32 = 1u << 2u;
+const MAT_TYPE_EMISSIVE: u32 = 1u << 3u;
+const MAT_TYPE_PRINCIPLED: u32 = 1u << 4u;
+
+//Set from `DEFAULT_WORKGROUP_SIZE` in gpu.rs, keep both equal
+const WORKGROUP_SIZE: u32 = 16u;
 
-var<private> world: array<Sphere, 4> = array<Sphere, 4>(
-    Sphere(
-        vec3<f32>(0, 0, -1.4), 
-        0.5, 
-        Material(MAT_TYPE_DIFFUSE, vec3<f32>(0.1, 0.1, 0.5), 1.0)),
-    Sphere(
-        vec3<f32>(-1, 0, -1), 
-        0.5, 
-        Material(MAT_TYPE_TRANSPARENT, vec3<f32>(0.8, 0.8, 0.8), 1.5)),
-    Sphere(
-        vec3<f32>(1, 0, -1), 
-        0.5, 
-        Material(MAT_TYPE_METALIC, vec3<f32>(0.8, 0.6, 0.2), 1.0)),
-    Sphere(
-        vec3<f32>(0, -20000.5, -1), 
-        20000.0, 
-        Material(MAT_TYPE_DIFFUSE, vec3<f32>(0.8, 0.8, 0.0), 1.0))
-);
+const PRIMITIVE_SPHERE: u32 = 0u;
+const PRIMITIVE_TRIANGLE: u32 = 1u;
 
 //Entry point
-@compute @workgroup_size(16, 16)
+@compute @workgroup_size(WORKGROUP_SIZE, WORKGROUP_SIZE)
 fn main(@builtin(global_invocation_id) global_ix: vec3<u32>) {
+    //Workgroups on the edges can go past the image
+    if global_ix.x >= args.width || global_ix.y >= args.height { return; }
+
     let uv = vec2<f32>(vec2<i32>(global_ix.xy)) / vec2<f32>(f32(args.width), f32(args.height));
 
-    let camera = new_camera(CAMERA_POSITION, CAMERA_LOOK_AT, CAMERA_FOCAL_LENGTH);
-    let viewport = new_viewport(2.0, args, camera);
+    let viewport = new_viewport(args, camera);
 
-    let frag_color = get_color(camera.position, vec2<f32>(f32(global_ix.x), f32(global_ix.y)), viewport);
-    textureStore(output_texture, vec2<i32>(global_ix.xy), frag_color);
-}
+    var frag_color = get_color(camera.position, vec2<f32>(f32(global_ix.x), f32(global_ix.y)), viewport);
 
-fn new_camera(position: vec3<f32>, look_at: vec3<f32>, focal_length: f32) -> Camera {
-    let forward = normalize(look_at - position);
-    let right = cross(forward, GLOBAL_UP);
-    let up = cross(right, forward);
+    //Running average of every sample since the last reset
+    let index = global_ix.y * args.width + global_ix.x;
+    if args.accumulated_samples > 0u
+    {
+        frag_color = mix(accumulation[index], frag_color, f32(args.samples) / f32(args.accumulated_samples + args.samples));
+    }
+    accumulation[index] = frag_color;
 
-    return Camera(position, up, forward, right, focal_length);
+    textureStore(output_texture, vec2<i32>(global_ix.xy), frag_color);
 }
 
-fn new_viewport(size: f32, args: Args, camera: Camera) -> Viewport {
+fn new_viewport(args: Args, camera: Camera) -> Viewport {
+    let aspect_ratio = f32(args.width) / f32(args.height);
+    var size = camera.size;
+    var height = size / aspect_ratio;
+    if camera.vertical_fov > 0.0
+    {
+        height = 2.0 * camera.focal_length * tan(camera.vertical_fov / 2.0);
+        size = height * aspect_ratio;
+    }
+
     let u: vec3<f32> = camera.right * size;
-    let v: vec3<f32> = camera.up * -(size * (f32(args.height) / f32(args.width)));
+    let v: vec3<f32> = camera.up * -height;
 
     let origin: vec3<f32> = camera.position + camera.forward * camera.focal_length - u / 2.0 - v / 2.0;
 
     let delta_u: vec3<f32> = u / f32(args.width);
     let delta_v: vec3<f32> = v / f32(args.height);
 
-    let pixel_origin: vec3<f32> = origin + 0.5 * (delta_u * delta_v);
+    let pixel_origin: vec3<f32> = origin + 0.5 * (delta_u + delta_v);
 
     return Viewport(origin, args, size, u, v, delta_u, delta_v, pixel_origin);
 }
@@ -141,89 +359,121 @@ fn get_color(ray_origin: vec3<f32>, pixel_position: vec2<f32>, viewport: Viewpor
     
     for (var i: u32 = 0; i < args.samples; i++) 
     {
-        color += render_pixel_sample(ray_origin, pixel_position, viewport, i) / f32(args.samples);
+        let sample = args.accumulated_samples + i;
+        color += render_pixel_sample(ray_origin, pixel_position, viewport, sample) / f32(args.samples);
     }
 
     return vec4<f32>(color, 1.0);
 }
 
 fn render_pixel_sample(ray_origin: vec3<f32>, pixel_position: vec2<f32>, viewport: Viewport, sample: u32) -> vec3<f32> {
-    let random_vec = pcg3d(vec3<u32>(u32(pixel_position.x), u32(pixel_position.y), sample));
-    var random_vec_n = normalize(vec3<f32>(f32(random_vec.x), f32(random_vec.y), f32(random_vec.z)));
-    random_vec_n *= 2.0;
-    random_vec_n -= 1.0;
-    
-    let viewport_point = viewport.origin + (pixel_position.x + random_vec_n.x) * viewport.delta_u + (pixel_position.y + random_vec_n.y) * viewport.delta_v;
-    var ray = Ray(ray_origin, viewport_point - ray_origin);
+    //Uniform offset of up to a pixel in each direction, like the CPU renderers
+    let offset = (random_floats(vec3<u32>(vec2<u32>(pixel_position), sample) ^ vec3<u32>(0u, 0u, 0x40000000u)).xy - 0.5) * 2.0;
+
+    let viewport_point = viewport.origin + (pixel_position.x + offset.x) * viewport.delta_u + (pixel_position.y + offset.y) * viewport.delta_v;
+
+    //Thin lens, rays leave a random point of the lens towards the point in focus
+    let focus_point = ray_origin + (viewport_point - ray_origin) * (camera.focus_distance / camera.focal_length);
+    let lens = random_floats(vec3<u32>(vec2<u32>(pixel_position), sample) ^ vec3<u32>(0u, 0u, 0x20000000u));
+    let radius = camera.aperture / 2.0 * sqrt(lens.x);
+    let angle = 2.0 * PI * lens.y;
+    let lens_point = ray_origin + radius * (cos(angle) * camera.right + sin(angle) * camera.up);
+
+    var ray = Ray(lens_point, focus_point - lens_point);
 
-    return get_ray_color(ray, sample);
+    return get_ray_color(ray, vec2<u32>(pixel_position), sample);
 }
 
-fn get_ray_color(ray: Ray, sample: u32) -> vec3<f32> {
-    var ray_hits = array<RaycastHit, MAX_BOUNCE>();
+fn get_ray_color(ray: Ray, pixel: vec2<u32>, sample: u32) -> vec3<f32> {
+    var radiance = vec3<f32>(0.0, 0.0, 0.0);
+    var throughput = vec3<f32>(1.0, 1.0, 1.0);
 
     var n_ray = Ray(ray.origin, ray.direction);
 
     //Casting ray
-    var i: u32 = 0;
-    for (;i < MAX_BOUNCE; i++)
+    for (var i: u32 = 0; i <= args.max_bounces; i++)
     {
-        //Get closest hit
-        var hit: RaycastHit = intersect_sphere(world[0], n_ray);
-        for (var i: u32 = 1; i < 4; i++) 
-        {
-            let o_hit = intersect_sphere(world[i], n_ray);
-            if !hit.has_hit || o_hit.has_hit && o_hit.distance <= hit.distance { hit = o_hit; }
-        }
+        //Get closest hit, misses hit the emissive sky
+        let hit = ray_cast(n_ray);
+
+        radiance += throughput * hit.material.emission;
+        if i == args.max_bounces { break; }
 
-        ray_hits[i] = hit;
+        let seed = vec3<u32>(pixel, sample * args.max_bounces + i);
 
-        //Handle hit
-        if hit.has_hit 
+        //Handle bounce
+        switch hit.material.mat_type
         {
-            //Handle bounce
-            switch hit.material.mat_type
+            case MAT_TYPE_DIFFUSE: 
+            {
+                //Cosine weighted, the cosine and the pdf cancel out with the 1/PI of the BRDF
+                let normal = faceForward(hit.normal, n_ray.direction, hit.normal);
+                let u = random_floats(seed);
+                n_ray = Ray(hit.point, orthonormal_basis(normal) * sample_cosine_direction(u.xy));
+                throughput *= hit.material.color;
+            }
+            case MAT_TYPE_METALIC: 
             {
-                case MAT_TYPE_DIFFUSE: 
+                let normal = faceForward(hit.normal, n_ray.direction, hit.normal);
+                let view = -normalize(n_ray.direction);
+                let alpha = max(hit.material.roughness * hit.material.roughness, 1e-4);
+
+                //Rough metals reflect on a microfacet sampled among the visible ones
+                var half = normal;
+                var masking = 1.0;
+                if hit.material.roughness > 0.0
                 {
-                    let random_u32vec = pcg3d(vec3<u32>(i, i * sample, sample));
-                    var random_n_f32vec = normalize(vec3<f32>(f32(random_u32vec.x), f32(random_u32vec.y), f32(random_u32vec.z)));
-                    random_n_f32vec *= 2.0;
-                    random_n_f32vec -= 1.0;
-                    let new_dir = hit.normal + random_n_f32vec;
-                    n_ray = Ray(hit.point, hit.normal + new_dir);
+                    let basis = orthonormal_basis(normal);
+                    let u = random_floats(seed).xy;
+                    half = basis * sample_ggx_visible_normal(view * basis, alpha, u);
                 }
-                case MAT_TYPE_METALIC: 
+
+                let direction = reflect(-view, half);
+                let cos_out = dot(direction, normal);
+                if cos_out <= 0.0 { return radiance; }
+                if hit.material.roughness > 0.0 { masking = ggx_masking(dot(view, normal), cos_out, alpha); }
+
+                throughput *= schlick_reflectance(hit.material.color, dot(view, half)) * masking;
+                n_ray = Ray(hit.point, direction);
+            }
+            case MAT_TYPE_PRINCIPLED:
+            {
+                let u = random_floats(seed);
+                let bounce = principled_bounce(hit.material, n_ray.direction, hit.normal, u);
+                throughput *= bounce.attenuation;
+                n_ray = Ray(hit.point, bounce.direction);
+            }
+            case MAT_TYPE_TRANSPARENT:
+            {
+                //Volumes are assumed to be surrounded by air
+                let is_inside = dot(hit.normal, n_ray.direction) > 0.0;
+                let normal = select(hit.normal, -hit.normal, is_inside);
+                let ior_ratio = select(1.0 / hit.material.ior, hit.material.ior, is_inside);
+
+                //Beer-Lambert absorption along the path through the volume
+                if is_inside
                 {
-                    n_ray = Ray(hit.point, reflect(n_ray.direction, hit.normal));
+                    throughput *= exp(-hit.material.absorption * distance(n_ray.origin, hit.point));
                 }
-                case MAT_TYPE_TRANSPARENT:
-                {
-                    if dot(hit.normal, n_ray.direction) < 0
-                    {
-                        n_ray = Ray(hit.point, refract(n_ray.direction, hit.normal, 1.0 / hit.material.ior));
-                    }
-                    else
-                    {
-                        n_ray = Ray(hit.point, refract(n_ray.direction, hit.normal, hit.material.ior));
-                    }
-                } 
-                default: { break; }
+
+                let u = random_floats(seed);
+                throughput *= hit.material.color;
+                n_ray = Ray(hit.point, dielectric_direction(n_ray.direction, normal, ior_ratio, u.x));
             }
-            continue;
+            //Lights and the sky absorb the ray
+            default: { return radiance; }
         }
 
-        break;
-    }
-
-    //Mixing color of ray bounce
-    var color = vec3<f32>(1.0, 1.0, 1.0);
-    for (var j: u32 = 0; j <= i; j++) 
-    {
-        color *= ray_hits[j].material.color;
+        //Surviving paths are brightened by the odds of being cut, keeping the estimate unbiased
+        if i >= RUSSIAN_ROULETTE_DEPTH
+        {
+            let survival = min(max(throughput.x, max(throughput.y, throughput.z)), 0.95);
+            if random_floats(seed ^ vec3<u32>(0u, 0u, 0x80000000u)).x >= survival { break; }
+            throughput /= survival;
+        }
     }
 
-    return color;
+    return radiance;
 }
 
 fn background_color(ray_direction: vec3<f32>) -> vec3<f32> {
@@ -234,6 +484,58 @@ fn background_color(ray_direction: vec3<f32>) -> vec3<f32> {
     return mix(GROUND_COLOR, SKY_COLOR, blend);
 }
 
+fn ray_cast(ray: Ray) -> RaycastHit {
+    var hit: RaycastHit = no_hit(ray);
+    let inverse_direction = 1.0 / ray.direction;
+
+    //Stackless traversal using skip links
+    var node_index: u32 = 0;
+    while node_index < args.bvh_node_count
+    {
+        let node = bvh[node_index];
+
+        if !intersect_aabb(node, ray, inverse_direction, hit.distance) 
+        {
+            node_index = node.skip;
+            continue;
+        }
+
+        if node.count == 0 
+        {
+            node_index++;
+            continue;
+        }
+
+        for (var i: u32 = node.first; i < node.first + node.count; i++) 
+        {
+            let primitive = primitives[i];
+            var o_hit: RaycastHit;
+            switch primitive.kind
+            {
+                case PRIMITIVE_SPHERE: { o_hit = intersect_sphere(world[primitive.index], ray); }
+                case PRIMITIVE_TRIANGLE: { o_hit = intersect_triangle(triangles[primitive.index], ray); }
+                default: { o_hit = no_hit(ray); }
+            }
+            if o_hit.has_hit && o_hit.distance < hit.distance { hit = o_hit; }
+        }
+        node_index = node.skip;
+    }
+
+    return hit;
+}
+
+fn intersect_aabb(node: BvhNode, ray: Ray, inverse_direction: vec3<f32>, max_distance: f32) -> bool {
+    let t_0 = (node.min - ray.origin) * inverse_direction;
+    let t_1 = (node.max - ray.origin) * inverse_direction;
+    let t_min = min(t_0, t_1);
+    let t_max = max(t_0, t_1);
+
+    let t_near = max(max(max(t_min.x, t_min.y), t_min.z), 0.0);
+    let t_far = min(min(min(t_max.x, t_max.y), t_max.z), max_distance);
+
+    return t_near <= t_far;
+}
+
 fn intersect_sphere(sphere: Sphere, ray: Ray) -> RaycastHit {
     let ray_sphere = sphere.position - ray.origin;
 
@@ -242,14 +544,79 @@ fn intersect_sphere(sphere: Sphere, ray: Ray) -> RaycastHit {
     let c = dot(ray_sphere, ray_sphere) - sphere.radius * sphere.radius;
 
     let discriminant = h * h - a * c;
-    let t = (h - sqrt(discriminant)) / a;
-    if discriminant < 0.0 || t < 0.001 {
-        let skyMat = Material(MAT_TYPE_DIFFUSE, background_color(ray.direction), 1.0);
-        return RaycastHit(false, bitcast<f32>(0x7F800000), ray.origin, ray.direction, skyMat);
+    if discriminant < 0.0 {
+        return no_hit(ray);
+    }
+
+    //Rays starting inside the sphere hit its far side
+    var t = (h - sqrt(discriminant)) / a;
+    if t < 0.001 {
+        t = (h + sqrt(discriminant)) / a;
+    }
+    if t < 0.001 {
+        return no_hit(ray);
     }
 
     let point = ray.origin + ray.direction * t;
     let normal = normalize(point - sphere.position);
 
-    return RaycastHit(true, t, point, normal, sphere.material);
+    return RaycastHit(true, t, point, normal, materials[sphere.material]);
+}
+
+//Watertight intersection from [https://jcgt.org/published/0002/01/05/]
+fn intersect_triangle(triangle: Triangle, ray: Ray) -> RaycastHit {
+    //Permute axes so that the direction is mostly along z
+    let abs_direction = abs(ray.direction);
+    var kz: u32 = 2;
+    if abs_direction.x > abs_direction.y && abs_direction.x > abs_direction.z { kz = 0u; }
+    else if abs_direction.y > abs_direction.z { kz = 1u; }
+    var kx = (kz + 1u) % 3u;
+    var ky = (kx + 1u) % 3u;
+    if ray.direction[kz] < 0.0 
+    {
+        let k = kx;
+        kx = ky;
+        ky = k;
+    }
+
+    //Shear so that the ray goes along +z
+    let shear = vec3<f32>(ray.direction[kx], ray.direction[ky], 1.0) / ray.direction[kz];
+
+    let a = triangle.vertices[0] - ray.origin;
+    let b = triangle.vertices[1] - ray.origin;
+    let c = triangle.vertices[2] - ray.origin;
+    let a_xy = vec2<f32>(a[kx] - shear.x * a[kz], a[ky] - shear.y * a[kz]);
+    let b_xy = vec2<f32>(b[kx] - shear.x * b[kz], b[ky] - shear.y * b[kz]);
+    let c_xy = vec2<f32>(c[kx] - shear.x * c[kz], c[ky] - shear.y * c[kz]);
+
+    //Scaled barycentric coordinates
+    let uvw = vec3<f32>(
+        c_xy.x * b_xy.y - c_xy.y * b_xy.x,
+        a_xy.x * c_xy.y - a_xy.y * c_xy.x,
+        b_xy.x * a_xy.y - b_xy.y * a_xy.x
+    );
+
+    let determinant = uvw.x + uvw.y + uvw.z;
+    if (any(uvw < vec3<f32>(0.0)) && any(uvw > vec3<f32>(0.0))) || determinant == 0.0 {
+        return no_hit(ray);
+    }
+
+    let t = dot(uvw, shear.z * vec3<f32>(a[kz], b[kz], c[kz])) / determinant;
+    if t < 0.001 {
+        return no_hit(ray);
+    }
+
+    let barycentric = uvw / determinant;
+    let geometric_normal = normalize(cross(triangle.vertices[1] - triangle.vertices[0], triangle.vertices[2] - triangle.vertices[0]));
+    var normal = normalize(barycentric.x * triangle.normals[0] + barycentric.y * triangle.normals[1] + barycentric.z * triangle.normals[2]);
+    if dot(normal, geometric_normal) < 0.0 { normal = -normal; }
+
+    let point = ray.origin + ray.direction * t;
+
+    return RaycastHit(true, t, point, normal, materials[triangle.material]);
+}
+
+fn no_hit(ray: Ray) -> RaycastHit {
+    let skyMat = Material(vec3<f32>(0.0), MAT_TYPE_EMISSIVE, background_color(ray.direction), 1.0, vec3<f32>(0.0), 1.0, 0.0, 0.0, 0.0, 0.0, 0.0);
+    return RaycastHit(false, bitcast<f32>(0x7F800000), ray.origin, ray.direction, skyMat);
 }
\ No newline at end of file
@@ -1,7 +1,9 @@
 #![allow(dead_code)]
 
+use std::{fs::File, io::BufWriter, path::Path};
+
 use crate::colors::Rgba;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use wgpu::util::DeviceExt;
 
 pub struct Texture {
@@ -40,6 +42,21 @@ impl Texture {
         bytemuck::cast_slice(self.pixels.as_slice())
     }
 
+    pub fn save_png(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create output file {}", path.display()))?;
+
+        let mut encoder =
+            png::Encoder::new(BufWriter::new(file), self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(self.data()))
+            .with_context(|| format!("Failed to write PNG to {}", path.display()))
+    }
+
     pub fn into_wgpu_texture(
         self,
         device: &wgpu::Device,
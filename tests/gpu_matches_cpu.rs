@@ -0,0 +1,63 @@
+use std::{fs::File, path::Path, process::Command};
+
+//Largest difference allowed between the mean of each channel, out of 255
+const TOLERANCE: f64 = 1.5;
+
+//Renders the default scene, returns None when the mode can't run on this machine
+//The size isn't a multiple of the workgroup size so that the edges are checked too
+fn render(mode: &str, output: &Path) -> Option<()> {
+    let result = Command::new(env!("CARGO_BIN_EXE_wgpu_raytracer"))
+        .args(["--headless", "-m", mode, "-s", "64"])
+        .args(["--width", "70", "--height", "50"])
+        .arg("-o")
+        .arg(output)
+        .output()
+        .expect("Running the raytracer");
+
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    if mode == "gpu" && stderr.contains("Failed to get adapter") {
+        return None;
+    }
+    assert!(result.status.success(), "{mode} render failed: {stderr}");
+
+    Some(())
+}
+
+fn mean_color(path: &Path) -> [f64; 3] {
+    let decoder = png::Decoder::new(File::open(path).expect("Opening the render"));
+    let mut reader = decoder.read_info().expect("Reading the PNG header");
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data).expect("Decoding the render");
+    assert_eq!(info.color_type, png::ColorType::Rgba);
+
+    let pixels = &data[..info.buffer_size()];
+    let mut sum = [0.0; 3];
+    for pixel in pixels.chunks_exact(4) {
+        for (channel, &value) in sum.iter_mut().zip(pixel) {
+            *channel += value as f64;
+        }
+    }
+
+    let pixel_count = (pixels.len() / 4) as f64;
+    sum.map(|channel| channel / pixel_count)
+}
+
+#[test]
+fn gpu_matches_cpu() {
+    let directory = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let (cpu_output, gpu_output) = (directory.join("cpu.png"), directory.join("gpu.png"));
+
+    render("multi-thread", &cpu_output).unwrap();
+    if render("gpu", &gpu_output).is_none() {
+        eprintln!("No GPU adapter available, skipping");
+        return;
+    }
+
+    let (cpu, gpu) = (mean_color(&cpu_output), mean_color(&gpu_output));
+    for (cpu_channel, gpu_channel) in cpu.iter().zip(gpu) {
+        assert!(
+            (cpu_channel - gpu_channel).abs() <= TOLERANCE,
+            "Mean colors differ, CPU {cpu:?} and GPU {gpu:?}"
+        );
+    }
+}